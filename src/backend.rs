@@ -0,0 +1,339 @@
+//! The interface between git-cache's orchestration logic and git itself.
+//!
+//! Everything that the cloner and prefetcher need from git goes through
+//! [`GitBackend`], so that locking, submodule recursion and update decisions
+//! can be exercised without real repositories or network access.
+//! [`CliBackend`] is the implementation used in practice, it runs `git`.
+
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+
+use crate::{lfs, partial, retry, shared, GitCacheRepo, GitRepo, SubmoduleSpec, TrueOr};
+
+pub trait GitBackend: Send + Sync {
+    /// Returns `true` if `repo` is the top level of a repository (bare or not).
+    fn is_initialized(&self, repo: &GitRepo) -> Result<bool>;
+
+    /// Returns `true` if `commit` resolves to a commit in `repo`.
+    fn has_commit(&self, repo: &GitRepo, commit: &str) -> Result<bool>;
+
+    /// Creates the mirror for `cache_repo`, which does not exist yet.
+    fn mirror(&self, cache_repo: &GitCacheRepo) -> Result<()>;
+
+    /// Updates an existing mirror from its upstream.
+    fn fetch(&self, cache_repo: &GitCacheRepo) -> Result<()>;
+
+    /// Fetches `commit` (a full commit id) into an existing mirror, for
+    /// commits that are not reachable from any of the upstream's refs.
+    fn fetch_commit(&self, cache_repo: &GitCacheRepo, commit: &str) -> Result<()>;
+
+    /// Clones `cache_repo` into `target_path`, pointing `origin` at the
+    /// upstream URL.
+    fn clone_from_cache(
+        &self,
+        cache_repo: &GitCacheRepo,
+        target_path: &Utf8Path,
+        extra_args: &[String],
+    ) -> Result<()>;
+
+    /// Clones `url` into `target_path` without involving the cache.
+    fn clone_direct(&self, url: &str, target_path: &Utf8Path, extra_args: &[String]) -> Result<()>;
+
+    /// Checks out `commit` (detached) in the working tree of `repo`.
+    fn checkout(&self, repo: &GitRepo, commit: &str) -> Result<()>;
+
+    /// Restricts the working tree of `repo` to `sparse_paths`.
+    fn sparse_checkout(&self, repo: &GitRepo, sparse_paths: &[String]) -> Result<()>;
+
+    /// Lists the submodules of `repo`.
+    ///
+    /// With `rev` set, `.gitmodules` and the submodule commits are read from
+    /// that revision (used for bare mirrors), otherwise from the working tree
+    /// and index.
+    fn list_submodules(&self, repo: &GitRepo, rev: Option<&str>) -> Result<Vec<SubmoduleSpec>>;
+
+    /// Returns the commits that the refs of `repo` matching `patterns` point
+    /// to, like `git for-each-ref` matches them.
+    fn list_refs(&self, repo: &GitRepo, patterns: &[String]) -> Result<Vec<String>>;
+
+    /// Registers the (already cloned) submodule at `path` in `repo`.
+    fn init_submodule(&self, repo: &GitRepo, path: &str) -> Result<()>;
+}
+
+/// Runs the `git` command line tool.
+///
+/// With the `gix` feature, read-only queries are answered using gitoxide.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CliBackend;
+
+impl GitBackend for CliBackend {
+    fn is_initialized(&self, repo: &GitRepo) -> Result<bool> {
+        repo.is_initialized()
+    }
+
+    fn has_commit(&self, repo: &GitRepo, commit: &str) -> Result<bool> {
+        repo.has_commit(commit)
+    }
+
+    fn mirror(&self, cache_repo: &GitCacheRepo) -> Result<()> {
+        let path = &cache_repo.repo.path;
+
+        if let Some(filter) = &cache_repo.filter {
+            println!("git-cache: creating partial mirror (--filter={filter})");
+        }
+
+        // Read-only tiers are not locked, they are maintained by someone
+        // else and are expected to only ever grow.
+        let mut references = Vec::new();
+        for reference in &cache_repo.reference_paths {
+            // read-only tiers are usually owned by someone else
+            let reference_repo = GitRepo {
+                path: reference.clone(),
+                safe_directory: true,
+            };
+            if reference_repo.is_initialized()? {
+                println!("git-cache: using {reference} as reference");
+                references.push(reference);
+            }
+        }
+
+        with_alternates(cache_repo, |url| {
+            cache_repo.retry.run(
+                "error mirroring repository",
+                || {
+                    shared::create_dir_all(path, cache_repo.shared)?;
+
+                    let mut clone_cmd = cache_repo.git_for_new_mirror();
+                    clone_cmd.arg("clone").arg("--mirror");
+                    clone_cmd.args(retry::progress_arg());
+                    if let Some(filter) = &cache_repo.filter {
+                        clone_cmd.arg(format!("--filter={filter}"));
+                    }
+                    // the objects stay in the read-only tiers, saving the
+                    // space is what they are for
+                    for reference in &references {
+                        clone_cmd.arg("--reference").arg(reference);
+                    }
+                    clone_cmd.arg("--").arg(url).arg(path);
+                    Ok(clone_cmd)
+                },
+                // an interrupted clone would look like a mirror
+                || match std::fs::remove_dir_all(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                    _ => Ok(()),
+                },
+            )?;
+
+            // the mirror stays a mirror of the upstream
+            if url != cache_repo.url {
+                cache_repo
+                    .repo
+                    .set_config("remote.origin.url", &cache_repo.url)?;
+            }
+            Ok(())
+        })?;
+
+        if cache_repo.filter.is_some() {
+            partial::serve_filtered(&cache_repo.repo)?;
+        }
+
+        fetch_lfs(cache_repo)
+    }
+
+    fn fetch(&self, cache_repo: &GitCacheRepo) -> Result<()> {
+        let filter = partial::filter(&cache_repo.repo)?;
+        with_alternates(cache_repo, |url| {
+            cache_repo.retry.run(
+                "error updating repository",
+                || {
+                    let mut fetch_cmd = cache_repo.repo.git();
+                    fetch_cmd.arg("fetch").args(retry::progress_arg());
+                    if url == cache_repo.url {
+                        fetch_cmd.arg("origin");
+                    } else {
+                        // the refs `origin` would fetch, without pointing
+                        // it elsewhere
+                        if let Some(filter) = &filter {
+                            fetch_cmd.arg(format!("--filter={filter}"));
+                        }
+                        fetch_cmd
+                            .arg("--")
+                            .arg(url)
+                            .arg("+refs/*:refs/*")
+                            .arg("^refs/gitcache/*");
+                    }
+                    Ok(fetch_cmd)
+                },
+                || Ok(()),
+            )
+        })?;
+
+        fetch_lfs(cache_repo)
+    }
+
+    fn fetch_commit(&self, cache_repo: &GitCacheRepo, commit: &str) -> Result<()> {
+        // keep it referenced, so it doesn't get garbage collected
+        let refspec = format!("+{commit}:refs/gitcache/commits/{commit}");
+        with_alternates(cache_repo, |url| {
+            cache_repo.retry.run(
+                "error fetching commit",
+                || {
+                    let mut fetch_cmd = cache_repo.repo.git();
+                    fetch_cmd
+                        .arg("fetch")
+                        .arg("--no-tags")
+                        .args(retry::progress_arg())
+                        .arg("--")
+                        .arg(url)
+                        .arg(&refspec);
+                    Ok(fetch_cmd)
+                },
+                || Ok(()),
+            )
+        })
+    }
+
+    fn clone_from_cache(
+        &self,
+        cache_repo: &GitCacheRepo,
+        target_path: &Utf8Path,
+        extra_args: &[String],
+    ) -> Result<()> {
+        let safe_directory = cache_repo
+            .repo
+            .safe_directory
+            .then_some(cache_repo.repo.path.as_path());
+
+        let mut clone_cmd = direct_clone(extra_args, safe_directory);
+
+        // Checking out needs objects that a partial mirror may not have, so
+        // that has to wait until the clone knows where to get them.
+        let filter = partial::filter(&cache_repo.repo)?;
+        if filter.is_some() {
+            clone_cmd.arg("--no-checkout");
+        }
+
+        // Smudging LFS files needs an LFS endpoint, which the mirror isn't
+        // as a git remote. Skip it and pull the LFS files from the mirror's
+        // LFS storage afterwards.
+        let use_lfs = lfs::available() && lfs::has_objects(&cache_repo.repo);
+        if use_lfs {
+            clone_cmd.env("GIT_LFS_SKIP_SMUDGE", "1");
+        }
+
+        clone_cmd
+            .arg("--")
+            .arg(cache_repo.repo.path.as_str())
+            .arg(target_path)
+            .status()?
+            .success()
+            .true_or(anyhow!("cloning failed"))?;
+
+        Command::new("git")
+            .arg("-C")
+            .arg(target_path)
+            .arg("remote")
+            .arg("set-url")
+            .arg("origin")
+            .arg(&cache_repo.url)
+            .status()?
+            .success()
+            .true_or(anyhow!("error updating remote url"))?;
+
+        let target_repo = GitRepo {
+            path: target_path.to_path_buf(),
+            safe_directory: false,
+        };
+        let no_checkout = extra_args
+            .iter()
+            .any(|arg| arg == "--no-checkout" || arg == "-n");
+
+        if let Some(filter) = &filter {
+            partial::use_mirror_as_promisor(&target_repo, &cache_repo.repo, filter)?;
+            if !no_checkout {
+                partial::checkout_head(&target_repo)?;
+            }
+        }
+
+        if use_lfs && !no_checkout {
+            lfs::pull(&target_repo, &cache_repo.repo)?;
+        }
+
+        Ok(())
+    }
+
+    fn clone_direct(&self, url: &str, target_path: &Utf8Path, extra_args: &[String]) -> Result<()> {
+        direct_clone(extra_args, None)
+            .arg("--")
+            .arg(url)
+            .arg(target_path)
+            .status()?
+            .success()
+            .true_or(anyhow!("cloning failed"))
+    }
+
+    fn checkout(&self, repo: &GitRepo, commit: &str) -> Result<()> {
+        repo.set_config("advice.detachedHead", "false")?;
+        repo.checkout(commit)
+    }
+
+    fn sparse_checkout(&self, repo: &GitRepo, sparse_paths: &[String]) -> Result<()> {
+        repo.sparse_checkout(sparse_paths)
+    }
+
+    fn list_submodules(&self, repo: &GitRepo, rev: Option<&str>) -> Result<Vec<SubmoduleSpec>> {
+        match rev {
+            Some(rev) => repo.submodules_at(rev),
+            None => repo.submodules(),
+        }
+    }
+
+    fn list_refs(&self, repo: &GitRepo, patterns: &[String]) -> Result<Vec<String>> {
+        repo.ref_commits(patterns)
+    }
+
+    fn init_submodule(&self, repo: &GitRepo, path: &str) -> Result<()> {
+        repo.init_submodule(path)
+    }
+}
+
+/// Runs `f` with the upstream URL of `cache_repo`, then with each of its
+/// alternates until `f` succeeds.
+fn with_alternates(cache_repo: &GitCacheRepo, mut f: impl FnMut(&str) -> Result<()>) -> Result<()> {
+    let mut result = f(&cache_repo.url);
+    for alternate in &cache_repo.alternates {
+        let Err(e) = &result else {
+            break;
+        };
+        println!("git-cache: {e:#}, trying alternate {alternate}...");
+        result = f(alternate);
+    }
+    result
+}
+
+/// Fetches the LFS objects of the mirror, falling back to alternates like
+/// fetching the mirror itself.
+fn fetch_lfs(cache_repo: &GitCacheRepo) -> Result<()> {
+    with_alternates(cache_repo, |url| {
+        let remote = if url == cache_repo.url { "origin" } else { url };
+        lfs::fetch_all(&cache_repo.repo, remote, &cache_repo.retry)
+    })
+}
+
+/// Returns a `git clone` command, up to (but excluding) the repository and
+/// target path.
+fn direct_clone(pass_through_args: &[String], safe_directory: Option<&Utf8Path>) -> Command {
+    let mut clone_cmd = Command::new("git");
+    if let Some(safe_directory) = safe_directory {
+        clone_cmd
+            .arg("-c")
+            .arg(format!("safe.directory={safe_directory}"));
+    }
+    clone_cmd.arg("clone").arg("--shared").args(pass_through_args);
+    clone_cmd
+}
+
+#[cfg(test)]
+pub(crate) mod fake;
@@ -0,0 +1,245 @@
+//! An in-memory [`GitBackend`] for unit tests.
+//!
+//! Repositories are sets of commits plus a list of submodules. Upstreams are
+//! registered by URL, every other repository (mirrors, clones) lives at its
+//! path. Only the target directories of clones are created on disk, as the
+//! cloner checks those.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::GitBackend;
+use crate::{GitCacheRepo, GitRepo, SubmoduleSpec};
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FakeRepo {
+    pub commits: BTreeSet<String>,
+    /// commits of upstreams that can only be fetched by their id
+    pub hidden_commits: BTreeSet<String>,
+    pub submodules: Vec<SubmoduleSpec>,
+    pub head: Option<String>,
+    pub initialized_submodules: Vec<String>,
+}
+
+impl FakeRepo {
+    pub fn new(commits: &[&str]) -> Self {
+        Self {
+            commits: commits.iter().map(|commit| commit.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_submodule(mut self, path: &str, url: &str, commit: &str) -> Self {
+        self.submodules.push(SubmoduleSpec::new(
+            path.into(),
+            url.into(),
+            commit.into(),
+            None,
+        ));
+        self
+    }
+}
+
+#[derive(Default)]
+struct State {
+    upstreams: HashMap<String, FakeRepo>,
+    repos: HashMap<Utf8PathBuf, FakeRepo>,
+    calls: Vec<String>,
+}
+
+#[derive(Default)]
+pub(crate) struct FakeBackend {
+    state: Mutex<State>,
+}
+
+impl FakeBackend {
+    /// Adds (or replaces) the upstream repository at `url`.
+    pub fn set_upstream(&self, url: &str, repo: FakeRepo) {
+        let mut state = self.state.lock().unwrap();
+        state.upstreams.insert(url.to_string(), repo);
+    }
+
+    /// Returns the repository at `path`, if it exists.
+    pub fn repo(&self, path: &Utf8Path) -> Option<FakeRepo> {
+        self.state.lock().unwrap().repos.get(path).cloned()
+    }
+
+    /// Returns the calls made so far, like `"mirror <url>"`.
+    pub fn calls(&self) -> Vec<String> {
+        self.state.lock().unwrap().calls.clone()
+    }
+
+    /// Returns how often `call` was made.
+    pub fn count(&self, call: &str) -> usize {
+        self.calls().iter().filter(|c| *c == call).count()
+    }
+
+    fn log(state: &mut State, call: String) {
+        state.calls.push(call);
+    }
+
+    fn upstream(state: &State, url: &str) -> Result<FakeRepo> {
+        state
+            .upstreams
+            .get(url)
+            .cloned()
+            .ok_or_else(|| anyhow!("no upstream at {url}"))
+    }
+
+    fn clone_into(&self, source: FakeRepo, target_path: &Utf8Path) -> Result<()> {
+        std::fs::create_dir_all(target_path)?;
+        let mut state = self.state.lock().unwrap();
+        state.repos.insert(
+            target_path.to_path_buf(),
+            FakeRepo {
+                commits: source.commits,
+                submodules: source.submodules,
+                ..Default::default()
+            },
+        );
+        Ok(())
+    }
+}
+
+impl GitBackend for FakeBackend {
+    fn is_initialized(&self, repo: &GitRepo) -> Result<bool> {
+        Ok(self.state.lock().unwrap().repos.contains_key(repo.path()))
+    }
+
+    fn has_commit(&self, repo: &GitRepo, commit: &str) -> Result<bool> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .repos
+            .get(repo.path())
+            .is_some_and(|repo| repo.commits.contains(commit)))
+    }
+
+    fn mirror(&self, cache_repo: &GitCacheRepo) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::log(&mut state, format!("mirror {}", cache_repo.url()));
+        let mut upstream = Self::upstream(&state, cache_repo.url())?;
+        upstream.hidden_commits.clear();
+        let path = cache_repo.repo().path().to_path_buf();
+        if state.repos.insert(path, upstream).is_some() {
+            bail!("mirror of {} exists already", cache_repo.url());
+        }
+        Ok(())
+    }
+
+    fn fetch(&self, cache_repo: &GitCacheRepo) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::log(&mut state, format!("fetch {}", cache_repo.url()));
+        let mut upstream = Self::upstream(&state, cache_repo.url())?;
+        upstream.hidden_commits.clear();
+        let path = cache_repo.repo().path().to_path_buf();
+        state.repos.insert(path, upstream);
+        Ok(())
+    }
+
+    fn fetch_commit(&self, cache_repo: &GitCacheRepo, commit: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::log(&mut state, format!("fetch {} {commit}", cache_repo.url()));
+        let upstream = Self::upstream(&state, cache_repo.url())?;
+        if !upstream.commits.contains(commit) && !upstream.hidden_commits.contains(commit) {
+            bail!("{} has no commit {commit}", cache_repo.url());
+        }
+        let path = cache_repo.repo().path();
+        let mirror = state
+            .repos
+            .get_mut(path)
+            .ok_or_else(|| anyhow!("no mirror of {}", cache_repo.url()))?;
+        mirror.commits.insert(commit.to_string());
+        Ok(())
+    }
+
+    fn clone_from_cache(
+        &self,
+        cache_repo: &GitCacheRepo,
+        target_path: &Utf8Path,
+        _extra_args: &[String],
+    ) -> Result<()> {
+        let mirror = {
+            let mut state = self.state.lock().unwrap();
+            Self::log(
+                &mut state,
+                format!("clone {} {target_path}", cache_repo.url()),
+            );
+            state
+                .repos
+                .get(cache_repo.repo().path())
+                .cloned()
+                .ok_or_else(|| anyhow!("no mirror of {}", cache_repo.url()))?
+        };
+        self.clone_into(mirror, target_path)
+    }
+
+    fn clone_direct(
+        &self,
+        url: &str,
+        target_path: &Utf8Path,
+        _extra_args: &[String],
+    ) -> Result<()> {
+        let upstream = {
+            let mut state = self.state.lock().unwrap();
+            Self::log(&mut state, format!("clone-direct {url} {target_path}"));
+            Self::upstream(&state, url)?
+        };
+        self.clone_into(upstream, target_path)
+    }
+
+    fn checkout(&self, repo: &GitRepo, commit: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::log(&mut state, format!("checkout {} {commit}", repo.path()));
+        let repo = state
+            .repos
+            .get_mut(repo.path())
+            .ok_or_else(|| anyhow!("no repository at {}", repo.path()))?;
+        if !repo.commits.contains(commit) {
+            bail!("unknown commit {commit}");
+        }
+        repo.head = Some(commit.to_string());
+        Ok(())
+    }
+
+    fn sparse_checkout(&self, repo: &GitRepo, sparse_paths: &[String]) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::log(
+            &mut state,
+            format!("sparse-checkout {} {}", repo.path(), sparse_paths.join(" ")),
+        );
+        Ok(())
+    }
+
+    fn list_submodules(&self, repo: &GitRepo, _rev: Option<&str>) -> Result<Vec<SubmoduleSpec>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .repos
+            .get(repo.path())
+            .map(|repo| repo.submodules.clone())
+            .unwrap_or_default())
+    }
+
+    /// Every commit is the tip of some ref, patterns are ignored.
+    fn list_refs(&self, repo: &GitRepo, _patterns: &[String]) -> Result<Vec<String>> {
+        let state = self.state.lock().unwrap();
+        Ok(state
+            .repos
+            .get(repo.path())
+            .map(|repo| repo.commits.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn init_submodule(&self, repo: &GitRepo, path: &str) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        Self::log(&mut state, format!("init-submodule {} {path}", repo.path()));
+        let repo = state
+            .repos
+            .get_mut(repo.path())
+            .ok_or_else(|| anyhow!("no repository at {}", repo.path()))?;
+        repo.initialized_submodules.push(path.to_string());
+        Ok(())
+    }
+}
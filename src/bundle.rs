@@ -0,0 +1,221 @@
+//! Export and import of cached mirrors as `git bundle` files.
+//!
+//! An export writes one bundle per mirror into a bundle directory, using the
+//! same `<host>/<path>` layout as the cache itself, plus a manifest that maps
+//! each bundle file back to its upstream URL. An import reads that manifest
+//! and creates the corresponding mirrors from the bundles.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Context as _, Error, Result};
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+
+use crate::{GitCache, GitCacheRepo, TrueOr};
+
+/// Name of the manifest file inside a bundle directory.
+pub const BUNDLE_MANIFEST: &str = "manifest.txt";
+
+/// Maps bundle file paths (relative to the bundle directory) to upstream URLs.
+#[derive(Debug, Default)]
+pub struct BundleManifest {
+    entries: BTreeMap<Utf8PathBuf, String>,
+}
+
+impl BundleManifest {
+    /// Reads the manifest from `bundle_dir`, returning an empty one if it
+    /// does not exist yet.
+    pub fn read(bundle_dir: &Utf8Path) -> Result<Self, Error> {
+        let path = bundle_dir.join(BUNDLE_MANIFEST);
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading bundle manifest \"{path}\""))?;
+
+        Self::parse(&data).with_context(|| format!("parsing bundle manifest \"{path}\""))
+    }
+
+    fn parse(data: &str) -> Result<Self, Error> {
+        let mut entries = BTreeMap::new();
+        for (n, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (bundle, url) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| anyhow!("line {}: expected \"<bundle> <url>\"", n + 1))?;
+
+            // bundles must not be read from outside of the bundle directory
+            let bundle = Utf8PathBuf::from(bundle);
+            if !bundle.components().all(|component| {
+                matches!(component, Utf8Component::Normal(_) | Utf8Component::CurDir)
+            }) {
+                bail!(
+                    "line {}: bundle path \"{bundle}\" must be relative and must not contain \"..\"",
+                    n + 1
+                );
+            }
+
+            entries.insert(bundle, url.trim().to_string());
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn write(&self, bundle_dir: &Utf8Path) -> Result<(), Error> {
+        let path = bundle_dir.join(BUNDLE_MANIFEST);
+        let mut data = String::from("# git-cache bundle manifest: <bundle> <upstream url>\n");
+        for (bundle, url) in &self.entries {
+            data.push_str(&format!("{bundle} {url}\n"));
+        }
+        std::fs::write(&path, data).with_context(|| format!("writing bundle manifest \"{path}\""))
+    }
+
+    pub fn insert(&mut self, bundle: Utf8PathBuf, url: String) {
+        self.entries.insert(bundle, url);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Utf8PathBuf, &String)> {
+        self.entries.iter()
+    }
+}
+
+impl GitCache {
+    /// Writes a bundle for each of `urls` into `bundle_dir` and records them in
+    /// the directory's manifest. Existing manifest entries are kept.
+    ///
+    /// URLs that are not cached are skipped and returned.
+    pub fn export_bundles(
+        &self,
+        urls: &[String],
+        bundle_dir: &Utf8Path,
+    ) -> Result<Vec<String>, Error> {
+        std::fs::create_dir_all(bundle_dir)
+            .with_context(|| format!("creating bundle directory {bundle_dir}"))?;
+        // `git bundle create` runs inside the mirror, so use an absolute path
+        let bundle_dir = &bundle_dir.canonicalize_utf8()?;
+
+        let mut manifest = BundleManifest::read(bundle_dir)?;
+        let mut uncached = Vec::new();
+
+        for url in urls {
+            let cache_repo = self.repo(url);
+            let bundle = GitCacheRepo::repo_path_from_url(url).with_extension("bundle");
+
+            let lock = cache_repo.lockfile()?;
+            {
+                let _lock = lock.read()?;
+                if !cache_repo.repo.is_initialized()? {
+                    println!("git-cache: {url} is not cached, skipping");
+                    uncached.push(url.clone());
+                    continue;
+                }
+                println!("git-cache: exporting {url} to {bundle}...");
+                cache_repo.export_bundle(&bundle_dir.join(&bundle))?;
+            }
+
+            manifest.insert(bundle, url.clone());
+        }
+
+        manifest.write(bundle_dir)?;
+        Ok(uncached)
+    }
+
+    /// Creates mirrors for all bundles listed in `bundle_dir`'s manifest.
+    ///
+    /// Repositories that are already cached are left untouched.
+    pub fn import_bundles(&self, bundle_dir: &Utf8Path) -> Result<(), Error> {
+        let manifest = BundleManifest::read(bundle_dir)?;
+
+        for (bundle, url) in manifest.iter() {
+            let cache_repo = self.repo(url);
+
+            let mut lock = cache_repo.lockfile()?;
+            let _lock = lock.write()?;
+            if cache_repo.repo.is_initialized()? {
+                println!("git-cache: {url} already cached, skipping");
+                continue;
+            }
+
+            println!("git-cache: importing {url} from {bundle}...");
+            cache_repo.import_bundle(&bundle_dir.join(bundle))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl GitCacheRepo {
+    fn export_bundle(&self, bundle_path: &Utf8Path) -> Result<()> {
+        if let Some(parent) = bundle_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("creating bundle directory {parent}"))?;
+        }
+
+        self.repo
+            .git()
+            .arg("bundle")
+            .arg("create")
+            .arg(bundle_path)
+            .arg("--all")
+            .status()?
+            .success()
+            .true_or(anyhow!("error creating bundle"))
+    }
+
+    fn import_bundle(&self, bundle_path: &Utf8Path) -> Result<()> {
+        crate::shared::create_dir_all(&self.repo.path, self.shared)?;
+        self.git_for_new_mirror()
+            .arg("clone")
+            .arg("--mirror")
+            .arg("--")
+            .arg(bundle_path)
+            .arg(&self.repo.path)
+            .status()?
+            .success()
+            .true_or(anyhow!("error importing bundle"))?;
+
+        // point the mirror at the real upstream so later updates work
+        self.repo.set_config("remote.origin.url", &self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifest() {
+        let manifest = BundleManifest::parse(
+            "# comment\n\
+             github.com/foo/bar.bundle https://github.com/foo/bar\n\
+             \n\
+             ./local/baz.bundle  /srv/baz.git\n",
+        )
+        .unwrap();
+        assert_eq!(
+            manifest
+                .iter()
+                .map(|(bundle, url)| (bundle.as_str(), url.as_str()))
+                .collect::<Vec<_>>(),
+            [
+                ("./local/baz.bundle", "/srv/baz.git"),
+                ("github.com/foo/bar.bundle", "https://github.com/foo/bar"),
+            ]
+        );
+    }
+
+    #[test]
+    fn manifest_paths_stay_inside_bundle_dir() {
+        for line in [
+            "../../x.bundle https://github.com/foo/bar",
+            "foo/../../x.bundle https://github.com/foo/bar",
+            "/etc/x.bundle https://github.com/foo/bar",
+        ] {
+            assert!(BundleManifest::parse(line).is_err(), "{line}");
+        }
+        assert!(BundleManifest::parse("bar.bundle").is_err());
+    }
+}
@@ -0,0 +1,205 @@
+//! Git dependencies of Cargo projects (`git cache prefetch --cargo-lock`).
+//!
+//! `Cargo.lock` records git dependencies as
+//! `source = "git+<url>[?branch=...|tag=...|rev=...]#<commit>"`. These are
+//! prefetched along with their locked commit, and can be redirected to the
+//! cache using source replacement in `.cargo/config.toml` (see
+//! [`config_snippet()`]), like `cargo vendor` does for vendored sources.
+
+use std::fmt::Write as _;
+
+use anyhow::{anyhow, bail, Context as _, Error, Result};
+use camino::Utf8Path;
+use serde::Deserialize;
+
+use crate::prefetch::PrefetchEntry;
+use crate::GitCache;
+
+#[derive(Deserialize)]
+struct CargoLock {
+    #[serde(default)]
+    package: Vec<Package>,
+}
+
+#[derive(Deserialize)]
+struct Package {
+    source: Option<String>,
+}
+
+/// A git source from `Cargo.lock`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct GitSource {
+    /// the source as Cargo identifies it, without the locked commit
+    pub source: String,
+    /// the repository URL
+    pub url: String,
+    /// `branch`, `tag` or `rev`, with its value
+    pub reference: Option<(String, String)>,
+    /// the locked commit
+    pub commit: String,
+}
+
+impl GitSource {
+    /// Parses a `Cargo.lock` source, returning `None` if it isn't a git
+    /// source.
+    pub fn parse(source: &str) -> Result<Option<Self>, Error> {
+        let Some(rest) = source.strip_prefix("git+") else {
+            return Ok(None);
+        };
+        let (without_commit, commit) = rest
+            .rsplit_once('#')
+            .ok_or_else(|| anyhow!("git source without locked commit: {source}"))?;
+
+        let mut url =
+            url::Url::parse(without_commit).with_context(|| format!("parsing source {source}"))?;
+        let reference = url
+            .query_pairs()
+            .find(|(key, _)| matches!(key.as_ref(), "branch" | "tag" | "rev"))
+            .map(|(key, value)| (key.into_owned(), value.into_owned()));
+        url.set_query(None);
+
+        Ok(Some(Self {
+            source: format!("git+{without_commit}"),
+            url: url.to_string(),
+            reference,
+            commit: commit.to_string(),
+        }))
+    }
+
+    pub fn prefetch_entry(&self) -> PrefetchEntry {
+        PrefetchEntry {
+            commit: Some(self.commit.clone()),
+            ..PrefetchEntry::new(self.url.clone())
+        }
+    }
+}
+
+/// Reads the git sources from the `Cargo.lock` at `path`.
+pub fn read_lock(path: &Utf8Path) -> Result<Vec<GitSource>, Error> {
+    let data = std::fs::read_to_string(path).with_context(|| format!("reading \"{path}\""))?;
+    parse_lock(&data).with_context(|| format!("parsing \"{path}\""))
+}
+
+/// Returns the (distinct) git sources of the packages in `data`.
+pub fn parse_lock(data: &str) -> Result<Vec<GitSource>, Error> {
+    let lock: CargoLock = toml::from_str(data)?;
+
+    let mut sources = Vec::new();
+    for source in lock.package.iter().filter_map(|p| p.source.as_deref()) {
+        if let Some(source) = GitSource::parse(source)? {
+            sources.push(source);
+        }
+    }
+    sources.sort();
+    sources.dedup();
+
+    Ok(sources)
+}
+
+/// First line of the settings written by [`config_snippet()`], telling them
+/// apart from files that must not be overwritten.
+const CONFIG_HEADER: &str = "# written by `git cache prefetch --cargo-lock`, do not edit\n";
+
+/// Fails if `path` exists and wasn't written by [`config_snippet()`], so
+/// overwriting it would lose settings.
+pub fn check_config_file(path: &Utf8Path) -> Result<()> {
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return Ok(());
+    };
+    if !contents.starts_with(CONFIG_HEADER) {
+        bail!("{path} exists and was not written by git-cache, not overwriting it");
+    }
+    Ok(())
+}
+
+/// Returns `.cargo/config.toml` settings replacing `sources` with their
+/// mirrors in `cache`, to be written to a file of their own.
+pub fn config_snippet(cache: &GitCache, sources: &[GitSource]) -> Result<String, Error> {
+    let mut snippet = String::from(CONFIG_HEADER);
+
+    for (n, source) in sources.iter().enumerate() {
+        let mirror = std::path::absolute(cache.repo(&source.url).repo().path())?;
+        let mirror_url = url::Url::from_directory_path(&mirror)
+            .map_err(|_| anyhow!("invalid mirror path {}", mirror.display()))?;
+        let reference = match &source.reference {
+            Some((key, value)) => format!("{key} = {}\n", toml_string(value)),
+            None => String::new(),
+        };
+        let name = format!("git-cache-{n}");
+
+        writeln!(
+            snippet,
+            "[source.{}]\ngit = {}\n{reference}replace-with = \"{name}\"\n",
+            toml_string(&source.source),
+            toml_string(&source.url),
+        )?;
+        writeln!(
+            snippet,
+            "[source.{name}]\ngit = {}\n{reference}",
+            toml_string(mirror_url.as_str().trim_end_matches('/')),
+        )?;
+    }
+
+    Ok(snippet)
+}
+
+fn toml_string(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn git_sources() {
+        let sources = parse_lock(
+            r#"
+version = 4
+
+[[package]]
+name = "a"
+version = "0.1.0"
+source = "git+https://github.com/org/repo?branch=main#0123456789abcdef0123456789abcdef01234567"
+
+[[package]]
+name = "b"
+version = "0.1.0"
+source = "git+https://github.com/org/repo?branch=main#0123456789abcdef0123456789abcdef01234567"
+
+[[package]]
+name = "c"
+version = "0.2.0"
+source = "git+ssh://git@example.com/c.git#89abcdef0123456789abcdef0123456789abcdef"
+
+[[package]]
+name = "d"
+version = "1.0.0"
+source = "registry+https://github.com/rust-lang/crates.io-index"
+
+[[package]]
+name = "local"
+version = "0.1.0"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            sources,
+            [
+                GitSource {
+                    source: "git+https://github.com/org/repo?branch=main".into(),
+                    url: "https://github.com/org/repo".into(),
+                    reference: Some(("branch".into(), "main".into())),
+                    commit: "0123456789abcdef0123456789abcdef01234567".into(),
+                },
+                GitSource {
+                    source: "git+ssh://git@example.com/c.git".into(),
+                    url: "ssh://git@example.com/c.git".into(),
+                    reference: None,
+                    commit: "89abcdef0123456789abcdef0123456789abcdef".into(),
+                },
+            ]
+        );
+    }
+}
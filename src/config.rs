@@ -0,0 +1,160 @@
+//! Per-URL settings from git configuration.
+//!
+//! Settings live in `gitcache` sections named by a URL prefix, e.g.:
+//!
+//! ```text
+//! [gitcache "https://chromium.googlesource.com/"]
+//!     filter = blob:none
+//! ```
+//!
+//! If multiple prefixes match a URL, the longest one wins.
+//!
+//! `alternate` URLs are tried in order when the upstream can't be reached
+//! while creating or updating a mirror. They replace the prefix, so one
+//! section can cover all repositories of a host:
+//!
+//! ```text
+//! [gitcache "https://github.com/"]
+//!     alternate = https://git.example.com/github-mirror/
+//! ```
+
+use anyhow::{Error, Result};
+
+#[derive(Debug, Default, Clone)]
+pub struct UrlConfig {
+    sections: Vec<UrlSection>,
+}
+
+#[derive(Debug, Clone)]
+struct UrlSection {
+    prefix: String,
+    /// object filter for new mirrors (`git clone --filter`)
+    filter: Option<String>,
+    /// replacements for `prefix` to fetch from if the upstream fails
+    alternates: Vec<String>,
+}
+
+impl UrlConfig {
+    /// Reads the global git configuration (system, global and user files).
+    pub fn from_globals() -> Result<Self, Error> {
+        Ok(Self::from_git_config(&gix_config::File::from_globals()?))
+    }
+
+    pub fn from_git_config(config: &gix_config::File) -> Self {
+        let Some(sections) = config.sections_by_name("gitcache") else {
+            return Self::default();
+        };
+
+        let sections = sections
+            .filter_map(|section| {
+                let prefix = section.header().subsection_name()?.to_string();
+                let filter = section.value("filter").map(|value| value.to_string());
+                let alternates = section
+                    .values("alternate")
+                    .iter()
+                    .map(|value| value.to_string())
+                    .collect();
+                Some(UrlSection {
+                    prefix,
+                    filter,
+                    alternates,
+                })
+            })
+            .collect();
+
+        Self { sections }
+    }
+
+    /// Returns the object filter for new mirrors of `url`, if configured.
+    pub fn filter(&self, url: &str) -> Option<&str> {
+        self.matching(url)
+            .find_map(|section| section.filter.as_deref())
+    }
+
+    /// Returns the alternate URLs of `url`, in the order they should be tried.
+    pub fn alternates(&self, url: &str) -> Vec<String> {
+        self.matching(url)
+            .find(|section| !section.alternates.is_empty())
+            .map(|section| {
+                let rest = &url[section.prefix.len()..];
+                section
+                    .alternates
+                    .iter()
+                    .map(|alternate| format!("{alternate}{rest}"))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the sections matching `url`, longest prefix first.
+    fn matching<'a>(&'a self, url: &str) -> impl Iterator<Item = &'a UrlSection> {
+        let mut matching = self
+            .sections
+            .iter()
+            .filter(|section| url.starts_with(&section.prefix))
+            .collect::<Vec<_>>();
+        matching.sort_by_key(|section| std::cmp::Reverse(section.prefix.len()));
+        matching.into_iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let config = gix_config::File::try_from(
+            r#"
+[gitcache "https://example.com/"]
+    filter = tree:0
+[gitcache "https://example.com/big/"]
+    filter = blob:none
+[gitcache "https://example.com/big/small"]
+"#,
+        )
+        .unwrap();
+        let config = UrlConfig::from_git_config(&config);
+
+        assert_eq!(config.filter("https://example.com/repo"), Some("tree:0"));
+        assert_eq!(
+            config.filter("https://example.com/big/repo"),
+            Some("blob:none")
+        );
+        assert_eq!(
+            config.filter("https://example.com/big/small"),
+            Some("blob:none")
+        );
+        assert_eq!(config.filter("https://other.com/repo"), None);
+    }
+
+    #[test]
+    fn alternates() {
+        let config = gix_config::File::try_from(
+            r#"
+[gitcache "https://github.com/"]
+    alternate = https://mirror.example.com/github/
+    alternate = ssh://git@backup.example.com/github/
+[gitcache "https://github.com/org/repo"]
+    alternate = https://git.example.com/repo
+[gitcache "https://github.com/org/"]
+    filter = blob:none
+"#,
+        )
+        .unwrap();
+        let config = UrlConfig::from_git_config(&config);
+
+        assert_eq!(
+            config.alternates("https://github.com/org/other"),
+            [
+                "https://mirror.example.com/github/org/other",
+                "ssh://git@backup.example.com/github/org/other"
+            ]
+        );
+        assert_eq!(
+            config.alternates("https://github.com/org/repo"),
+            ["https://git.example.com/repo"]
+        );
+        assert!(config.alternates("https://gitlab.com/org/repo").is_empty());
+    }
+}
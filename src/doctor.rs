@@ -0,0 +1,310 @@
+//! Consistency checks for a cache directory (`git cache doctor`).
+
+use std::fmt;
+use std::process::Command;
+
+use anyhow::Result;
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::{GitCache, GitRepo};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Severity {
+    /// Something that will make cache operations fail.
+    Problem,
+    /// Something that git-cache handles, but plain git might not.
+    Hint,
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    pub path: Utf8PathBuf,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Problem => "problem",
+            Severity::Hint => "hint",
+        };
+        write!(f, "{severity}: {}: {}", self.path, self.message)
+    }
+}
+
+impl GitCache {
+    /// Checks all mirrors of the writable cache.
+    ///
+    /// In shared mode, this also checks the permissions and group ownership
+    /// that are needed for multiple users to work on the same cache.
+    pub fn doctor(&self) -> Result<Vec<Finding>> {
+        let mut findings = Vec::new();
+
+        #[cfg(unix)]
+        let cache_gid = {
+            use std::os::unix::fs::MetadataExt;
+            self.cache_base_dir.metadata()?.gid()
+        };
+
+        #[cfg(unix)]
+        if self.shared {
+            check_mode(
+                &self.cache_base_dir,
+                crate::shared::SHARED_DIR_MODE,
+                &mut findings,
+            )?;
+        }
+
+        for mirror_path in self.mirror_paths()? {
+            let repo = GitRepo {
+                path: mirror_path.clone(),
+                safe_directory: self.shared,
+            };
+
+            // outside of shared mode, git refuses to work in such mirrors,
+            // which would look like they weren't repositories at all
+            let dubious_ownership = is_dubious_ownership(&mirror_path)?;
+            if dubious_ownership && !self.shared {
+                findings.push(Finding {
+                    severity: Severity::Problem,
+                    path: mirror_path,
+                    message: "owned by another user, git refuses to use it (use shared mode or `safe.directory`)".into(),
+                });
+                continue;
+            }
+
+            if !repo.is_initialized()? {
+                findings.push(Finding {
+                    severity: Severity::Problem,
+                    path: mirror_path,
+                    message: "not a git repository (interrupted clone?)".into(),
+                });
+                continue;
+            }
+
+            // mirrors borrow objects from the read-only tiers they were
+            // created with
+            let alternates = mirror_path.join("objects/info/alternates");
+            if let Ok(alternates) = std::fs::read_to_string(alternates) {
+                for alternate in alternates.lines().filter(|line| !line.starts_with('#')) {
+                    if !Utf8Path::new(alternate).is_dir() {
+                        findings.push(Finding {
+                            severity: Severity::Problem,
+                            path: mirror_path.clone(),
+                            message: format!(
+                                "borrows objects from {alternate}, which doesn't exist anymore"
+                            ),
+                        });
+                    }
+                }
+            }
+
+            if !self.shared {
+                continue;
+            }
+
+            let shared_repository = repo.get_config("core.sharedRepository")?;
+            if !matches!(shared_repository.as_deref(), Some("group" | "true" | "1")) {
+                findings.push(Finding {
+                    severity: Severity::Problem,
+                    path: mirror_path.clone(),
+                    message: "core.sharedRepository is not set to \"group\"".into(),
+                });
+            }
+
+            #[cfg(unix)]
+            {
+                check_tree(&mirror_path, cache_gid, &mut findings)?;
+                check_mode(
+                    &mirror_path.with_extension("git.lock"),
+                    crate::shared::SHARED_FILE_MODE,
+                    &mut findings,
+                )?;
+            }
+
+            if dubious_ownership {
+                findings.push(Finding {
+                    severity: Severity::Hint,
+                    path: mirror_path,
+                    message: "owned by another user, plain git commands need it listed in `safe.directory`".into(),
+                });
+            }
+        }
+
+        Ok(findings)
+    }
+}
+
+/// Returns `true` if git refuses to work in `path` due to its ownership checks.
+fn is_dubious_ownership(path: &Utf8Path) -> Result<bool> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(path)
+        .arg("rev-parse")
+        .arg("--git-dir")
+        .output()?;
+
+    Ok(!output.status.success()
+        && String::from_utf8_lossy(&output.stderr).contains("dubious ownership"))
+}
+
+#[cfg(unix)]
+fn check_mode(path: &Utf8Path, mode: u32, findings: &mut Vec<Finding>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if !path.exists() {
+        return Ok(());
+    }
+
+    let actual = path.metadata()?.permissions().mode();
+    if actual & mode != mode {
+        findings.push(Finding {
+            severity: Severity::Problem,
+            path: path.to_path_buf(),
+            message: format!("mode is {:o}, needs at least {mode:o}", actual & 0o7777),
+        });
+    }
+
+    Ok(())
+}
+
+/// Checks group ownership and permissions of everything within a mirror.
+///
+/// Only the first offending path of each kind is reported, to keep the
+/// output readable for large mirrors.
+#[cfg(unix)]
+fn check_tree(mirror_path: &Utf8Path, gid: u32, findings: &mut Vec<Finding>) -> Result<()> {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let mut wrong_group = None;
+    let mut wrong_dir_mode = None;
+    let mut wrong_file_mode = None;
+
+    let mut stack = vec![mirror_path.to_path_buf()];
+    while let Some(path) = stack.pop() {
+        let metadata = path.symlink_metadata()?;
+        let mode = metadata.permissions().mode();
+
+        if metadata.gid() != gid && wrong_group.is_none() {
+            wrong_group = Some(path.clone());
+        }
+
+        if metadata.is_dir() {
+            if mode & crate::shared::SHARED_DIR_MODE != crate::shared::SHARED_DIR_MODE
+                && wrong_dir_mode.is_none()
+            {
+                wrong_dir_mode = Some(path.clone());
+            }
+            for entry in path.read_dir_utf8()? {
+                stack.push(entry?.into_path());
+            }
+        } else if metadata.is_file() && mode & 0o040 == 0 && wrong_file_mode.is_none() {
+            wrong_file_mode = Some(path.clone());
+        }
+    }
+
+    for (path, message) in [
+        (wrong_group, "group differs from the cache directory's group"),
+        (wrong_dir_mode, "directory is not group-writable or lacks setgid"),
+        (wrong_file_mode, "file is not group-readable"),
+    ] {
+        if let Some(path) = path {
+            findings.push(Finding {
+                severity: Severity::Problem,
+                path,
+                message: message.into(),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn git_init(path: &Utf8Path, args: &[&str]) {
+        assert!(Command::new("git")
+            .arg("init")
+            .arg("-q")
+            .arg("--bare")
+            .args(args)
+            .arg(path)
+            .status()
+            .unwrap()
+            .success());
+    }
+
+    fn messages(findings: &[Finding], base: &Utf8Path) -> Vec<(String, String)> {
+        findings
+            .iter()
+            .map(|finding| {
+                (
+                    finding.path.strip_prefix(base).unwrap().to_string(),
+                    finding.message.clone(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_interrupted_clones() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Utf8Path::from_path(dir.path()).unwrap();
+        let cache = GitCache::new(base.to_path_buf()).unwrap();
+
+        git_init(&base.join("example.com/ok.git"), &[]);
+        std::fs::create_dir_all(base.join("example.com/broken.git")).unwrap();
+
+        assert_eq!(
+            messages(&cache.doctor().unwrap(), base),
+            [(
+                "example.com/broken.git".to_string(),
+                "not a git repository (interrupted clone?)".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn finds_unshared_mirrors() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::set_permissions(base, std::fs::Permissions::from_mode(0o2775)).unwrap();
+        let cache = GitCache::new(base.to_path_buf()).unwrap().with_shared(true);
+
+        let ok = base.join("example.com/ok.git");
+        crate::shared::create_dir_all(&ok, true).unwrap();
+        git_init(&ok, &["--shared=group"]);
+        crate::shared::create_lock_file(&ok.with_extension("git.lock"), true).unwrap();
+
+        let unshared = base.join("example.com/unshared.git");
+        git_init(&unshared, &[]);
+        let lock = unshared.with_extension("git.lock");
+        std::fs::write(&lock, "").unwrap();
+        std::fs::set_permissions(&lock, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let findings = messages(&cache.doctor().unwrap(), base);
+        let expected = [
+            (
+                "example.com/unshared.git",
+                "core.sharedRepository is not set to \"group\"",
+            ),
+            (
+                "example.com/unshared.git",
+                "directory is not group-writable or lacks setgid",
+            ),
+            (
+                "example.com/unshared.git.lock",
+                "mode is 600, needs at least 60",
+            ),
+        ];
+        assert_eq!(
+            findings,
+            expected.map(|(path, message)| (path.to_string(), message.to_string()))
+        );
+    }
+}
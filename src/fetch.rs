@@ -0,0 +1,81 @@
+//! Fetching into existing clones through the cache (`git cache fetch`).
+
+use anyhow::{anyhow, bail, Error, Result};
+use camino::Utf8PathBuf;
+
+use crate::{GitCache, GitRepo};
+
+#[derive(Builder)]
+pub struct GitCacheFetcher {
+    cache: GitCache,
+    /// path of the clone, or any directory within it
+    #[builder(default = "Utf8PathBuf::from(\".\")")]
+    path: Utf8PathBuf,
+    #[builder(default = "String::from(\"origin\")")]
+    remote: String,
+    #[builder(default)]
+    prune: bool,
+}
+
+impl GitCacheFetcherBuilder {
+    pub fn do_fetch(&mut self) -> Result<(), Error> {
+        self.build()
+            .expect("GitCacheFetcher builder correctly set up")
+            .do_fetch()
+    }
+}
+
+impl GitCache {
+    pub fn fetcher(&self) -> GitCacheFetcherBuilder {
+        let mut fetcher = GitCacheFetcherBuilder::default();
+        fetcher.cache(self.clone());
+        fetcher
+    }
+}
+
+impl GitCacheFetcher {
+    /// Updates the mirror of the remote's URL, then fetches from the mirror.
+    ///
+    /// The fetched branches end up in `refs/remotes/<remote>/*`, exactly
+    /// where a direct `git fetch <remote>` would have put them.
+    fn do_fetch(&self) -> Result<(), Error> {
+        let toplevel = GitRepo {
+            path: self.path.clone(),
+            safe_directory: false,
+        }
+        .toplevel()?;
+        let target_repo = GitRepo {
+            path: toplevel,
+            safe_directory: false,
+        };
+
+        let remote = &self.remote;
+        let url = target_repo
+            .get_config(&format!("remote.{remote}.url"))?
+            .ok_or_else(|| anyhow!("{}: no remote named `{remote}`", target_repo.path))?;
+
+        if !self.cache.is_cacheable(&url) {
+            bail!("remote `{remote}` ({url}) is local, nothing to cache");
+        }
+
+        let cache_repo = self.cache.repo(&url);
+        let mut lock = cache_repo.lockfile()?;
+        {
+            let _lock = lock.write()?;
+            if !cache_repo.mirror()? {
+                println!("git-cache: updating cache for {url}...");
+                cache_repo.update()?;
+                if self.prune {
+                    cache_repo.prune()?;
+                }
+            }
+        }
+        {
+            let _lock = lock.read()?;
+            println!("git-cache: fetching {url} from cache...");
+            cache_repo.fetch_into(&target_repo, remote, self.prune)?;
+        }
+
+        Ok(())
+    }
+}
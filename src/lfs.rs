@@ -0,0 +1,104 @@
+//! Git LFS support.
+//!
+//! Mirrors of repositories that use LFS get all LFS objects fetched into
+//! their default LFS storage (`<mirror>/lfs`). Clones from such a mirror keep
+//! their own storage, but initially pull LFS objects from the mirror (as a
+//! `file://` LFS endpoint), which is only ever read from that way. After
+//! that, clones use the upstream LFS server, like any clone.
+//!
+//! All of this requires `git-lfs` to be installed, otherwise LFS files are
+//! left alone (as pointers, like git would without LFS).
+
+use std::process::Stdio;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8PathBuf;
+
+use crate::retry::RetryPolicy;
+use crate::{GitRepo, TrueOr};
+
+/// Returns `true` if `git-lfs` is installed.
+pub(crate) fn available() -> bool {
+    static AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *AVAILABLE.get_or_init(|| {
+        std::process::Command::new("git")
+            .arg("lfs")
+            .arg("version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    })
+}
+
+/// Returns the LFS storage directory of the mirror `repo`.
+pub(crate) fn storage(repo: &GitRepo) -> Utf8PathBuf {
+    repo.path.join("lfs")
+}
+
+/// Returns `true` if LFS objects have been fetched into the mirror `repo`.
+pub(crate) fn has_objects(repo: &GitRepo) -> bool {
+    storage(repo).join("objects").is_dir()
+}
+
+/// Returns `true` if the mirror `repo` uses LFS.
+///
+/// Only the top level `.gitattributes` of `HEAD` is checked, and mirrors
+/// that already contain LFS objects.
+pub(crate) fn used_by(repo: &GitRepo) -> Result<bool> {
+    if has_objects(repo) {
+        return Ok(true);
+    }
+
+    let Some(gitattributes) = repo.read_blob("HEAD:.gitattributes")? else {
+        return Ok(false);
+    };
+
+    Ok(String::from_utf8_lossy(&gitattributes)
+        .lines()
+        .any(|line| !line.trim_start().starts_with('#') && line.contains("filter=lfs")))
+}
+
+/// Fetches the LFS objects of all refs of the mirror `repo` from `remote`, a
+/// remote name or URL.
+pub(crate) fn fetch_all(repo: &GitRepo, remote: &str, retry: &RetryPolicy) -> Result<()> {
+    if !used_by(repo)? {
+        return Ok(());
+    }
+
+    if !available() {
+        println!(
+            "git-cache: warning: {} uses LFS, but git-lfs is not installed",
+            repo.path
+        );
+        return Ok(());
+    }
+
+    println!("git-cache: fetching LFS objects into {}...", repo.path);
+    retry.run(
+        "error fetching LFS objects",
+        || {
+            let mut fetch_cmd = repo.git();
+            fetch_cmd.arg("lfs").arg("fetch").arg("--all").arg(remote);
+            Ok(fetch_cmd)
+        },
+        || Ok(()),
+    )
+}
+
+/// Checks out the LFS files of a clone whose checkout skipped smudging,
+/// fetching them from the mirror `mirror`.
+///
+/// The mirror is only used for this pull, later ones fetch from upstream
+/// like usual, as new LFS objects are not in the mirror.
+pub(crate) fn pull(repo: &GitRepo, mirror: &GitRepo) -> Result<()> {
+    repo.git()
+        .arg("-c")
+        .arg(format!("lfs.url=file://{}", mirror.path))
+        .arg("lfs")
+        .arg("pull")
+        .status()?
+        .success()
+        .true_or(anyhow!("error checking out LFS files"))
+}
@@ -1,7 +1,9 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::ffi::OsStr;
 use std::io::BufRead;
-use std::sync::atomic::AtomicBool;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::{fs::File, process::Command};
 
@@ -11,10 +13,43 @@ use clap::{Arg, ArgAction, ArgMatches, ValueHint};
 use crossbeam::channel::Sender;
 use gix_config::file::init::Options;
 use gix_config::file::Metadata;
-use rayon::{prelude::*, ThreadPoolBuilder};
 
+use crate::backend::{CliBackend, GitBackend};
+use crate::config::UrlConfig;
+use crate::prefetch::{PrefetchEntry, PrefetchSummary};
+use crate::retry::RetryPolicy;
+use crate::scheduler::Scheduler;
+use crate::submodule::SubmoduleFilter;
+
+pub mod backend;
+pub mod bundle;
+pub mod cargo;
+pub mod config;
+pub mod doctor;
+pub mod fetch;
+mod lfs;
+pub mod manifest;
+#[cfg(feature = "gix")]
+mod native;
+mod partial;
+pub mod plan;
+pub mod prefetch;
+pub mod retry;
+mod scheduler;
+mod shared;
+pub mod submodule;
+pub mod sync;
+
+#[derive(Clone)]
 pub struct GitCache {
     cache_base_dir: Utf8PathBuf,
+    readonly_cache_dirs: Vec<Utf8PathBuf>,
+    shared: bool,
+    cache_local_repos: bool,
+    url_config: UrlConfig,
+    mirror_filter: Option<String>,
+    retry: RetryPolicy,
+    backend: Arc<dyn GitBackend>,
 }
 
 pub struct ScpScheme<'a> {
@@ -47,20 +82,269 @@ impl GitCache {
         std::fs::create_dir_all(&cache_base_dir)
             .with_context(|| format!("creating git cache base directory {cache_base_dir}"))?;
 
-        Ok(Self { cache_base_dir })
+        Ok(Self::with_base_dir(cache_base_dir))
+    }
+
+    /// Like [`GitCache::new()`], without creating the directory.
+    fn with_base_dir(cache_base_dir: Utf8PathBuf) -> Self {
+        Self {
+            cache_base_dir,
+            readonly_cache_dirs: Vec::new(),
+            shared: false,
+            cache_local_repos: false,
+            url_config: UrlConfig::default(),
+            mirror_filter: None,
+            retry: RetryPolicy::default(),
+            backend: Arc::new(CliBackend),
+        }
+    }
+
+    /// Replaces the git backend (by default, [`CliBackend`]).
+    pub fn with_backend(mut self, backend: Arc<dyn GitBackend>) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Enables shared mode, for cache directories used by multiple users of
+    /// the same Unix group.
+    ///
+    /// Mirrors get created with `core.sharedRepository=group`, directories
+    /// get group permissions and the setgid bit, lock files are made
+    /// group-writable, and mirrors owned by other users are treated as
+    /// `safe.directory`.
+    pub fn with_shared(mut self, shared: bool) -> Self {
+        self.shared = shared;
+        self
+    }
+
+    /// Also caches repositories given by a local path or `file://` URL.
+    ///
+    /// Those are usually cloned directly. Mirrors of local repositories live
+    /// below `local/` in the cache, this is mostly useful for testing.
+    pub fn with_cache_local_repos(mut self, cache_local_repos: bool) -> Self {
+        self.cache_local_repos = cache_local_repos;
+        self
+    }
+
+    /// Returns `true` if `url` should go through the cache.
+    pub fn is_cacheable(&self, url: &str) -> bool {
+        self.cache_local_repos || !repo_is_local(url)
+    }
+
+    /// Sets the per-URL settings, see [`UrlConfig`].
+    pub fn with_url_config(mut self, url_config: UrlConfig) -> Self {
+        self.url_config = url_config;
+        self
+    }
+
+    /// Creates new mirrors as partial clones using `filter` (e.g.,
+    /// `blob:none`), overriding per-URL settings.
+    ///
+    /// Clones from such mirrors fetch missing objects through the mirror.
+    /// Existing mirrors are not changed.
+    pub fn with_mirror_filter(mut self, filter: Option<String>) -> Self {
+        self.mirror_filter = filter;
+        self
+    }
+
+    /// Sets how network operations on mirrors (creating and updating them)
+    /// get retried, see [`RetryPolicy`].
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Adds read-only cache tiers.
+    ///
+    /// These are never written to. When a repository gets mirrored into the
+    /// writable cache, copies found in these tiers are used as `--reference`,
+    /// so only objects missing from them get downloaded.
+    pub fn with_readonly_cache_dirs(mut self, readonly_cache_dirs: Vec<Utf8PathBuf>) -> Self {
+        self.readonly_cache_dirs = readonly_cache_dirs;
+        self
+    }
+
+    /// Creates a cache from a list of directories, as used by `GIT_CACHE_DIR`.
+    ///
+    /// The first directory is the writable cache, all others are read-only tiers.
+    pub fn from_path_list(path_list: &str) -> Result<Self, Error> {
+        let dirs = std::env::split_paths(path_list)
+            .map(Utf8PathBuf::try_from)
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::from_dirs(dirs)
+    }
+
+    /// Creates a cache from the writable cache directory followed by the
+    /// read-only tiers, e.g. the values of [`clap_git_cache_dir_arg()`].
+    pub fn from_dirs(dirs: impl IntoIterator<Item = Utf8PathBuf>) -> Result<Self, Error> {
+        let mut dirs = dirs
+            .into_iter()
+            .filter(|path| !path.as_str().is_empty())
+            .map(|path| Utf8PathBuf::from(shellexpand::tilde(&path).as_ref()))
+            .collect::<Vec<_>>();
+
+        if dirs.is_empty() {
+            bail!("no git cache directory given");
+        }
+
+        let cache_base_dir = dirs.remove(0);
+        Ok(Self::new(cache_base_dir)?.with_readonly_cache_dirs(dirs))
     }
 
     pub fn cloner(&self) -> GitCacheClonerBuilder {
         let mut cloner = GitCacheClonerBuilder::default();
-        cloner.cache_base_dir(self.cache_base_dir.clone());
+        cloner.cache(self.clone());
         cloner
     }
 
     pub fn prefetcher(&self) -> GitCachePrefetcherBuilder {
         let mut prefetcher = GitCachePrefetcherBuilder::default();
-        prefetcher.cache_base_dir(self.cache_base_dir.clone());
+        prefetcher.cache(self.clone());
         prefetcher
     }
+
+    /// Returns the cache repository for `url`, including its read-only tiers.
+    pub fn repo(&self, url: &str) -> GitCacheRepo {
+        let url = &absolute_local_url(url);
+        let mut repo = GitCacheRepo::new(&self.cache_base_dir, url);
+        let repo_path = GitCacheRepo::repo_path_from_url(url);
+        repo.reference_paths = self
+            .readonly_cache_dirs
+            .iter()
+            .map(|dir| dir.join(&repo_path))
+            .collect();
+        repo.shared = self.shared;
+        repo.repo.safe_directory = self.shared;
+        repo.filter = self
+            .mirror_filter
+            .clone()
+            .or_else(|| self.url_config.filter(url).map(str::to_string));
+        repo.alternates = self.url_config.alternates(url);
+        repo.retry = self.retry.clone();
+        repo.backend = self.backend.clone();
+        repo
+    }
+
+    /// Returns the submodules of the working tree at `repo` that are
+    /// selected by `filter`.
+    ///
+    /// `location` is the path of `repo` below the top-level repository and
+    /// its nesting depth (`None` for the top-level repository itself).
+    /// Relative submodule URLs are resolved against `superproject_url`.
+    fn submodules(
+        &self,
+        repo: &GitRepo,
+        superproject_url: &str,
+        filter: &SubmoduleFilter,
+        location: Option<&(Utf8PathBuf, usize)>,
+    ) -> Result<Vec<SubmoduleSpec>> {
+        let (prefix, depth) = match location {
+            Some((path, depth)) => (path.as_path(), depth + 1),
+            None => (Utf8Path::new(""), 1),
+        };
+
+        let mut submodules = self.backend.list_submodules(repo, None)?;
+        for submodule in &mut submodules {
+            submodule.url = resolve_submodule_url(superproject_url, &submodule.url);
+        }
+        submodules.retain(|submodule| {
+            let path = prefix.join(&submodule.path);
+            let reason = filter.skip_reason(submodule, path.as_str(), depth);
+            if let Some(reason) = reason {
+                println!("git-cache: skipping submodule `{path}` ({reason})");
+            }
+            reason.is_none()
+        });
+        Ok(submodules)
+    }
+
+    /// Clones `submodule` of the working tree at `repo` through the cache.
+    ///
+    /// `location` is the submodule's path below the top-level repository and
+    /// its nesting depth. Nested submodules get queued on `scheduler`.
+    /// `init_lock` serializes registering submodules in `repo`, as git can't
+    /// update its configuration concurrently.
+    fn clone_submodule(
+        &self,
+        repo: &GitRepo,
+        submodule: &SubmoduleSpec,
+        options: &SubmoduleOptions,
+        location: (Utf8PathBuf, usize),
+        scheduler: &Scheduler,
+        init_lock: &Mutex<()>,
+    ) -> Result<()> {
+        let submodule_path = repo.path.join(&submodule.path);
+
+        let mut cloner = self.cloner();
+
+        cloner
+            .repository_url(submodule.url.clone())
+            .target_path(Some(submodule_path))
+            .recurse_all_submodules(options.filter.include.is_none())
+            .recurse_submodules(options.filter.include.clone())
+            .exclude_submodules(options.filter.exclude.clone())
+            .submodule_depth(options.filter.max_depth)
+            .submodule_location(Some(location))
+            .scheduler(Some(scheduler.clone()))
+            .shallow_submodules(options.shallow)
+            .remote_submodules(options.remote)
+            .also_filter_submodules(options.also_filter);
+
+        // With `remote`, the tip of the submodule's branch gets checked out
+        // instead of the recorded commit, after updating its mirror.
+        if options.remote {
+            cloner.update(true);
+
+            // without a branch, the upstream's default branch gets cloned
+            let branch = match submodule.branch.as_deref() {
+                // "." means the same branch as the superproject
+                Some(".") => Some(repo.current_branch()?.ok_or_else(|| {
+                    anyhow!(
+                        "submodule `{}` follows the superproject's branch, but {} is not on a branch",
+                        submodule.path,
+                        repo.path
+                    )
+                })?),
+                Some(branch) => Some(branch.to_string()),
+                None => None,
+            };
+            if let Some(branch) = branch {
+                cloner.extra_clone_args(Some(vec!["--branch".into(), branch]));
+            }
+        } else {
+            cloner
+                .commit(Some(submodule.commit.clone()))
+                .update(options.update);
+        }
+
+        cloner.do_clone()?;
+
+        let _lock = init_lock.lock().unwrap();
+        self.backend.init_submodule(repo, &submodule.path)?;
+
+        Ok(())
+    }
+
+    /// Returns the paths of all mirrors in the writable cache.
+    pub fn mirror_paths(&self) -> Result<Vec<Utf8PathBuf>> {
+        let mut mirrors = Vec::new();
+        let mut stack = vec![self.cache_base_dir.clone()];
+        while let Some(dir) = stack.pop() {
+            for entry in dir.read_dir_utf8()? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                if entry.path().extension() == Some("git") {
+                    mirrors.push(entry.into_path());
+                } else {
+                    stack.push(entry.into_path());
+                }
+            }
+        }
+        mirrors.sort();
+        Ok(mirrors)
+    }
 }
 
 #[macro_use]
@@ -68,7 +352,7 @@ extern crate derive_builder;
 
 #[derive(Builder)]
 pub struct GitCacheCloner {
-    cache_base_dir: Utf8PathBuf,
+    cache: GitCache,
     #[builder(setter(custom))]
     repository_url: String,
     #[builder(default = "true")]
@@ -86,6 +370,23 @@ pub struct GitCacheCloner {
     #[builder(default)]
     shallow_submodules: bool,
     #[builder(default)]
+    remote_submodules: bool,
+    /// globs of submodule paths (below the top-level repository) not to clone
+    #[builder(default)]
+    exclude_submodules: Vec<String>,
+    /// how many levels of nested submodules to clone (all if `None`)
+    #[builder(default)]
+    submodule_depth: Option<usize>,
+    /// also apply the cache's mirror filter (`--filter`) to submodule mirrors
+    #[builder(default)]
+    also_filter_submodules: bool,
+    /// path below the top-level repository and depth, if this is a submodule
+    #[builder(default, private)]
+    submodule_location: Option<(Utf8PathBuf, usize)>,
+    /// scheduler of the clone this is part of, if any
+    #[builder(default, private)]
+    scheduler: Option<Scheduler>,
+    #[builder(default)]
     commit: Option<String>,
     #[builder(default)]
     extra_clone_args: Option<Vec<String>>,
@@ -96,12 +397,21 @@ pub struct GitCacheCloner {
 impl GitCacheClonerBuilder {
     pub fn repository_url(&mut self, url: String) -> &mut Self {
         if self.cached.is_none() {
-            self.cached = Some(!repo_is_local(&url));
+            let cacheable = match &self.cache {
+                Some(cache) => cache.is_cacheable(&url),
+                None => !repo_is_local(&url),
+            };
+            self.cached = Some(cacheable);
         }
         self.repository_url = Some(url);
         self
     }
 
+    #[deprecated(note = "use `GitCache::cloner()` or `cache()` instead")]
+    pub fn cache_base_dir(&mut self, cache_base_dir: Utf8PathBuf) -> &mut Self {
+        self.cache(GitCache::with_base_dir(cache_base_dir))
+    }
+
     pub fn do_clone(&mut self) -> Result<(), Error> {
         self.build()
             .expect("GitCacheCloner builder correctly set up")
@@ -128,6 +438,53 @@ fn repo_is_local(url: &str) -> bool {
     }
 }
 
+/// Returns local paths as absolute paths, so they stay valid when used as
+/// `origin` of a clone. URLs are returned as they are.
+fn absolute_local_url(url: &str) -> String {
+    if url::Url::parse(url).is_ok() || url_is_scp_scheme(url) {
+        return url.to_string();
+    }
+    match std::path::absolute(url) {
+        Ok(path) => path.to_string_lossy().into_owned(),
+        Err(_) => url.to_string(),
+    }
+}
+
+/// Resolves a submodule URL relative to its superproject's URL, like git does
+/// for URLs starting with `./` or `../`.
+fn resolve_submodule_url(superproject_url: &str, url: &str) -> String {
+    if !(url.starts_with("./") || url.starts_with("../")) {
+        return url.to_string();
+    }
+
+    let mut base = superproject_url.trim_end_matches('/').to_string();
+    let mut separator = '/';
+    let mut rest = url;
+    loop {
+        if let Some(stripped) = rest.strip_prefix("./") {
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("../") {
+            rest = stripped;
+            // with scp-like URLs (`host:path`), the host stays
+            match base.rfind(['/', ':']) {
+                Some(pos) => {
+                    separator = base[pos..].chars().next().unwrap();
+                    base.truncate(pos);
+                }
+                None => base.clear(),
+            }
+        } else {
+            break;
+        }
+    }
+
+    if base.is_empty() {
+        rest.to_string()
+    } else {
+        format!("{base}{separator}{rest}")
+    }
+}
+
 fn url_split_scp_scheme(url: &str) -> Option<(usize, usize)> {
     let at = url.find('@');
     let colon = url.find(':');
@@ -153,126 +510,214 @@ impl GitCacheCloner {
         let target_path;
 
         if self.cached {
-            let cache_repo = GitCacheRepo::new(&self.cache_base_dir, &self.repository_url);
+            let cache_repo = self.cache.repo(&self.repository_url);
             target_path = cache_repo.target_path(self.target_path.as_ref())?;
 
             let mut lock = cache_repo.lockfile()?;
-            {
+            let mut update_mirror = || -> Result<()> {
                 let _lock = lock.write()?;
                 if !cache_repo.mirror()? {
-                    let try_update =
-                        wanted_commit.is_some_and(|commit| !cache_repo.has_commit(commit).unwrap());
-
-                    if self.update || try_update {
+                    // only look for the commit before updating if that might
+                    // make the update unnecessary
+                    let try_update = self.update
+                        || match wanted_commit {
+                            Some(commit) => !cache_repo.has_commit(commit)?,
+                            None => false,
+                        };
+
+                    if try_update {
                         println!("git-cache: updating cache for {repository}...");
                         cache_repo.update()?;
-                    }
 
-                    if let Some(commit) = wanted_commit {
-                        if try_update && !cache_repo.has_commit(commit)? {
-                            bail!("git-cache: {repository} does not contain commit {commit}");
+                        if let Some(commit) = wanted_commit {
+                            if !cache_repo.has_commit(commit)? {
+                                bail!("git-cache: {repository} does not contain commit {commit}");
+                            }
                         }
                     }
                 }
+                Ok(())
+            };
+            // Waiting for a network permit while holding the lock would hold
+            // up all other clones of this repository, so get it first.
+            if cache_repo.may_fetch(self.update, wanted_commit.map(String::as_str))? {
+                self.network(update_mirror)?;
+            } else {
+                update_mirror()?;
             }
             {
                 let _lock = lock.read()?;
-                cache_repo.clone(target_path.as_str(), self.extra_clone_args.as_ref())?;
+                cache_repo.clone(&target_path, self.extra_clone_args.as_deref())?;
             }
         } else {
             target_path =
                 target_path_from_url_maybe(&self.repository_url, self.target_path.as_ref())?;
 
-            direct_clone(
-                &self.repository_url,
-                target_path.as_str(),
-                self.extra_clone_args.as_ref(),
-            )?;
+            self.network(|| {
+                self.cache.backend.clone_direct(
+                    &self.repository_url,
+                    &target_path,
+                    self.extra_clone_args.as_deref().unwrap_or_default(),
+                )
+            })?;
         }
 
         let target_repo = GitRepo {
             path: target_path.clone(),
+            safe_directory: false,
         };
 
+        let backend = &self.cache.backend;
         if let Some(commit) = wanted_commit {
-            target_repo.set_config("advice.detachedHead", "false")?;
-            target_repo.checkout(commit)?;
+            backend.checkout(&target_repo, commit)?;
         }
         if let Some(sparse_paths) = self.sparse_paths.as_ref() {
-            target_repo.sparse_checkout(sparse_paths)?;
+            backend.sparse_checkout(&target_repo, sparse_paths)?;
         }
 
         if self.recurse_all_submodules || self.recurse_submodules.is_some() {
-            let filter = if !self.recurse_all_submodules {
-                self.recurse_submodules.clone()
-            } else {
-                None
+            let options = SubmoduleOptions {
+                filter: SubmoduleFilter {
+                    include: if !self.recurse_all_submodules {
+                        self.recurse_submodules.clone()
+                    } else {
+                        None
+                    },
+                    exclude: self.exclude_submodules.clone(),
+                    max_depth: self.submodule_depth,
+                },
+                shallow: self.shallow_submodules,
+                remote: self.remote_submodules,
+                update: self.update,
+                also_filter: self.also_filter_submodules,
             };
 
-            let cache = self.cache()?;
-
-            let jobs = self.jobs.unwrap_or(1);
+            // submodules of submodules are queued on the top-level clone's
+            // scheduler
+            match &self.scheduler {
+                Some(scheduler) => self.queue_submodules(&target_repo, options, scheduler)?,
+                None => Scheduler::run(self.jobs.unwrap_or(1), |scheduler| {
+                    self.queue_submodules(&target_repo, options, scheduler)
+                })?,
+            }
+        };
 
-            static RAYON_CONFIGURED: AtomicBool = AtomicBool::new(false);
+        Ok(())
+    }
 
-            if !RAYON_CONFIGURED.swap(true, std::sync::atomic::Ordering::AcqRel) {
-                let _ = ThreadPoolBuilder::new().num_threads(jobs).build_global();
+    /// Queues clones of the submodules of `target_repo` on `scheduler`.
+    fn queue_submodules(
+        &self,
+        target_repo: &GitRepo,
+        options: SubmoduleOptions,
+        scheduler: &Scheduler,
+    ) -> Result<()> {
+        let location = self.submodule_location.as_ref();
+        let submodules = self.cache.submodules(
+            target_repo,
+            &absolute_local_url(&self.repository_url),
+            &options.filter,
+            location,
+        )?;
+
+        let options = Arc::new(options);
+        let init_lock = Arc::new(Mutex::new(()));
+        for submodule in submodules {
+            let submodule_location = match location {
+                Some((path, depth)) => (path.join(&submodule.path), depth + 1),
+                None => (Utf8PathBuf::from(&submodule.path), 1),
+            };
+            let mut cache = self.cache.clone();
+            // like `git clone --filter`, which doesn't apply to submodules
+            // without `--also-filter-submodules`
+            if !options.also_filter {
+                cache.mirror_filter = None;
             }
-
-            target_repo
-                .get_submodules(filter)?
-                .par_iter()
-                .map(|submodule| {
-                    println!(
-                        "git-cache: cloning {} into {}...",
-                        submodule.url, submodule.path
-                    );
-                    target_repo.clone_submodule(
-                        submodule,
-                        &cache,
-                        self.shallow_submodules,
-                        self.update,
-                    )
-                })
-                .collect::<Result<Vec<_>, _>>()?;
-        };
+            let target_repo = target_repo.clone();
+            let options = options.clone();
+            let init_lock = init_lock.clone();
+
+            scheduler.spawn(move |scheduler| {
+                println!(
+                    "git-cache: cloning {} into {}...",
+                    submodule.url, submodule.path
+                );
+                cache.clone_submodule(
+                    &target_repo,
+                    &submodule,
+                    &options,
+                    submodule_location,
+                    scheduler,
+                    &init_lock,
+                )
+            });
+        }
 
         Ok(())
     }
 
+    /// Runs the network operation `f`, limited by the scheduler (if any).
+    fn network<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        match &self.scheduler {
+            Some(scheduler) => scheduler.network(f),
+            None => f(),
+        }
+    }
+
     pub fn cache(&self) -> Result<GitCache, anyhow::Error> {
-        GitCache::new(self.cache_base_dir.clone())
+        Ok(self.cache.clone())
     }
 }
 
 #[derive(Builder)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct GitCachePrefetcher {
-    cache_base_dir: Utf8PathBuf,
+    cache: GitCache,
+    #[builder(default)]
     repository_urls: Vec<String>,
+    /// repositories with per-repository options, e.g. from a list file
+    #[builder(default)]
+    entries: Vec<PrefetchEntry>,
     #[builder(default)]
     update: bool,
     #[builder(default)]
     recurse_all_submodules: bool,
     #[builder(default)]
     jobs: Option<usize>,
+    /// continue with the other repositories after a failure, instead of
+    /// skipping those that didn't start yet
+    #[builder(default)]
+    keep_going: bool,
+    /// ref patterns (as for `git for-each-ref`) whose submodules are
+    /// prefetched as well, including the commits they are pinned to
+    #[builder(default)]
+    submodule_refs: Vec<String>,
 }
 
 impl GitCachePrefetcherBuilder {
     pub fn validate(&self) -> Result<(), String> {
-        if let Some(urls) = &self.repository_urls {
-            for url in urls {
-                if repo_is_local(&url) {
-                    return Err(format!(
-                        "can only cache remote repositories, '{url}' is local"
-                    ));
-                }
+        let urls = self.repository_urls.iter().flatten();
+        let entry_urls = self.entries.iter().flatten().map(|entry| &entry.url);
+        for url in urls.chain(entry_urls) {
+            let cacheable = match &self.cache {
+                Some(cache) => cache.is_cacheable(url),
+                None => !repo_is_local(url),
+            };
+            if !cacheable {
+                return Err(format!(
+                    "can only cache remote repositories, '{url}' is local"
+                ));
             }
         }
         Ok(())
     }
 
-    pub fn do_prefetch(&mut self) -> Result<(), Error> {
+    #[deprecated(note = "use `GitCache::prefetcher()` or `cache()` instead")]
+    pub fn cache_base_dir(&mut self, cache_base_dir: Utf8PathBuf) -> &mut Self {
+        self.cache(GitCache::with_base_dir(cache_base_dir))
+    }
+
+    pub fn do_prefetch(&mut self) -> Result<PrefetchSummary, Error> {
         self.build()
             .expect("GitCachePrefetcher builder correctly set up")
             .do_prefetch()
@@ -280,14 +725,99 @@ impl GitCachePrefetcherBuilder {
 }
 
 enum Prefetch {
-    Done,
-    Url(String),
+    /// the results for the entries of the job for the given mirror
+    Done(Utf8PathBuf, Vec<(PrefetchEntry, Result<(), Error>)>),
+    Skipped(PrefetchJob),
+    /// the submodules of the repository at the given URL
+    Submodules(String, Vec<PrefetchEntry>),
+}
+
+/// The entries for a single mirror, prefetched together.
+struct PrefetchJob {
+    /// the path of the mirror
+    key: Utf8PathBuf,
+    entries: Vec<PrefetchEntry>,
+}
+
+/// Queues a job per mirror, for the entries not covered by an earlier job.
+///
+/// Entries for a mirror that has a job queued or running wait for it to
+/// finish, and then get queued together.
+struct PrefetchQueue<'a> {
+    cache: &'a GitCache,
+    sender: Sender<PrefetchJob>,
+    /// the commits queued for each mirror, with `None` for the mirror itself
+    queued: HashMap<Utf8PathBuf, HashSet<Option<String>>>,
+    /// the mirrors with a job queued or running, and the entries waiting
+    busy: HashMap<Utf8PathBuf, Vec<PrefetchEntry>>,
+    /// the number of jobs that are not done yet
+    left: usize,
+}
+
+impl PrefetchQueue<'_> {
+    fn add(&mut self, entries: impl IntoIterator<Item = PrefetchEntry>) {
+        let mut jobs: Vec<PrefetchJob> = Vec::new();
+        for entry in entries {
+            let key = self.cache.repo(&entry.url).repo().path().to_path_buf();
+            let queued = self.queued.entry(key.clone()).or_default();
+            // any job for a mirror covers the entries without commit
+            let new = queued.insert(None)
+                | entry
+                    .commit
+                    .as_ref()
+                    .is_some_and(|commit| queued.insert(Some(commit.clone())));
+            if !new {
+                continue;
+            }
+
+            if let Some(waiting) = self.busy.get_mut(&key) {
+                waiting.push(entry);
+            } else if let Some(job) = jobs.iter_mut().find(|job| job.key == key) {
+                job.entries.push(entry);
+            } else {
+                jobs.push(PrefetchJob {
+                    key,
+                    entries: vec![entry],
+                });
+            }
+        }
+
+        for job in jobs {
+            self.send(job);
+        }
+    }
+
+    fn send(&mut self, job: PrefetchJob) {
+        self.busy.insert(job.key.clone(), Vec::new());
+        self.left += 1;
+        let _ = self.sender.send(job);
+    }
+
+    /// Marks the job for the mirror `key` as done, queuing the entries that
+    /// waited for it.
+    fn done(&mut self, key: &Utf8Path) {
+        self.left -= 1;
+        if let Some(entries) = self.busy.remove(key) {
+            if !entries.is_empty() {
+                self.send(PrefetchJob {
+                    key: key.to_path_buf(),
+                    entries,
+                });
+            }
+        }
+    }
 }
 
 impl GitCachePrefetcher {
-    fn do_prefetch(&self) -> Result<(), Error> {
-        let (sender, receiver) = crossbeam::channel::unbounded::<String>();
+    /// Prefetches all repositories, returning what happened to each of
+    /// them.
+    ///
+    /// Unless `keep_going` is set, repositories that didn't start yet are
+    /// skipped after the first failure.
+    fn do_prefetch(&self) -> Result<PrefetchSummary, Error> {
+        let (sender, receiver) = crossbeam::channel::unbounded::<PrefetchJob>();
         let (sender2, receiver2) = crossbeam::channel::unbounded::<Prefetch>();
+        let failed = Arc::new(AtomicBool::new(false));
 
         let mut handles = Vec::new();
 
@@ -295,79 +825,188 @@ impl GitCachePrefetcher {
 
         for _ in 0..n_workers {
             let r = receiver.clone();
-            let cache_base_dir = self.cache_base_dir.clone();
+            let cache = self.cache.clone();
             let recurse = self.recurse_all_submodules;
             let update = self.update;
+            let keep_going = self.keep_going;
+            let submodule_refs = self.submodule_refs.clone();
             let sender2 = sender2.clone();
+            let failed = failed.clone();
 
             let handle = thread::spawn(move || {
-                for repository_url in r.iter() {
-                    if let Err(e) =
-                        prefetch_url(&repository_url, &cache_base_dir, update, recurse, &sender2)
-                    {
-                        println!("git-cache: error prefetching {repository_url}: {e}");
+                for job in r.iter() {
+                    if failed.load(Ordering::Relaxed) {
+                        let _ = sender2.send(Prefetch::Skipped(job));
+                        continue;
+                    }
+                    // a panic must not keep the job from being reported
+                    let results = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        prefetch_mirror(
+                            &job.entries,
+                            &cache,
+                            update,
+                            recurse,
+                            &submodule_refs,
+                            &sender2,
+                        )
+                    }))
+                    .unwrap_or_else(|_| Err(anyhow!("job panicked")));
+                    let results = match results {
+                        Ok(results) => results,
+                        Err(e) => job.entries.iter().map(|_| Err(anyhow!("{e:#}"))).collect(),
+                    };
+                    let results = job.entries.into_iter().zip(results).collect::<Vec<_>>();
+                    for (entry, result) in &results {
+                        match result {
+                            Ok(()) => println!("git-cache: prefetched {entry}"),
+                            Err(e) => {
+                                println!("git-cache: error prefetching {entry}: {e:#}");
+                                if !keep_going {
+                                    failed.store(true, Ordering::Relaxed);
+                                }
+                            }
+                        }
                     }
+                    let _ = sender2.send(Prefetch::Done(job.key, results));
                 }
             });
             handles.push(handle);
         }
 
-        for repository_url in &self.repository_urls {
-            let _ = sender2.send(Prefetch::Url(repository_url.clone()));
-        }
+        let entries = self.requested().collect::<Vec<_>>();
+        let mut summary = PrefetchSummary::default();
+
+        // repositories are prefetched once per mirror, however many
+        // superprojects share them or whichever URL they are referred to by
+        let mut queue = PrefetchQueue {
+            cache: &self.cache,
+            sender,
+            queued: HashMap::new(),
+            busy: HashMap::new(),
+            left: 0,
+        };
+        let mut names = HashMap::new();
+        let mut name = |url: &str| -> String {
+            let key = self.cache.repo(url).repo().path().to_path_buf();
+            names.entry(key).or_insert_with(|| url.to_string()).clone()
+        };
 
-        let mut left = 0usize;
-        let mut total = 0;
-        for prefetch in receiver2 {
-            match prefetch {
-                Prefetch::Done => left -= 1,
-                Prefetch::Url(url) => {
-                    left += 1;
-                    total += 1;
-                    let _ = sender.send(url);
-                }
+        for entry in &entries {
+            let root = name(&entry.url);
+            if !summary.graph.roots.contains(&root) {
+                summary.graph.roots.push(root);
             }
-            if left == 0 {
-                break;
+        }
+        queue.add(entries);
+
+        while queue.left > 0 {
+            match receiver2.recv()? {
+                Prefetch::Done(key, results) => {
+                    for (entry, result) in results {
+                        match result {
+                            Ok(()) => summary.succeeded.push(entry),
+                            Err(e) => summary.failed.push((entry, e)),
+                        }
+                    }
+                    queue.done(&key);
+                }
+                Prefetch::Skipped(job) => {
+                    summary.skipped.extend(job.entries);
+                    queue.done(&job.key);
+                }
+                Prefetch::Submodules(parent, entries) => {
+                    let parent = name(&parent);
+                    for entry in &entries {
+                        let submodule = name(&entry.url);
+                        summary
+                            .graph
+                            .submodules
+                            .entry(parent.clone())
+                            .or_default()
+                            .insert(submodule);
+                    }
+                    queue.add(entries);
+                }
             }
         }
 
         // Close the channel
-        drop(sender);
+        drop(queue);
 
         // Wait for all threads to finish
         for handle in handles {
             handle.join().unwrap();
         }
 
-        println!("git-cache: finished pre-fetching {total} repositories.");
+        println!(
+            "git-cache: finished pre-fetching {} repositories ({} failed, {} skipped).",
+            summary.total(),
+            summary.failed.len(),
+            summary.skipped.len()
+        );
+
+        Ok(summary)
+    }
 
-        Ok(())
+    /// Returns the repositories to prefetch, as given.
+    fn requested(&self) -> impl Iterator<Item = PrefetchEntry> + '_ {
+        self.repository_urls
+            .iter()
+            .map(|url| PrefetchEntry::new(url.clone()))
+            .chain(self.entries.iter().cloned())
     }
 
     pub fn cache(&self) -> Result<GitCache, anyhow::Error> {
-        GitCache::new(self.cache_base_dir.clone())
+        Ok(self.cache.clone())
     }
 }
 
+#[derive(Clone)]
 pub struct GitRepo {
     path: Utf8PathBuf,
+    /// the repository might be owned by another user, so mark it as
+    /// `safe.directory` for our own git invocations
+    safe_directory: bool,
 }
 
 pub struct GitCacheRepo {
     url: String,
     repo: GitRepo,
+    /// copies of this repository in read-only cache tiers (if they exist)
+    reference_paths: Vec<Utf8PathBuf>,
+    /// see [`GitCache::with_shared()`]
+    shared: bool,
+    /// object filter used when creating the mirror
+    filter: Option<String>,
+    /// URLs to fetch from if the upstream fails
+    alternates: Vec<String>,
+    retry: RetryPolicy,
+    backend: Arc<dyn GitBackend>,
 }
 
 impl GitRepo {
+    pub fn path(&self) -> &Utf8Path {
+        &self.path
+    }
+
     fn git(&self) -> std::process::Command {
         let mut command = Command::new("git");
         command.arg("-C").arg(&self.path);
+        if self.safe_directory {
+            command
+                .arg("-c")
+                .arg(format!("safe.directory={}", self.path));
+        }
 
         command
     }
 
     fn is_initialized(&self) -> Result<bool> {
+        #[cfg(feature = "gix")]
+        if let Ok(initialized) = native::is_initialized(&self.path) {
+            return Ok(initialized);
+        }
+
         Ok(self.path.is_dir()
             && matches!(
                 self.git()
@@ -381,6 +1020,11 @@ impl GitRepo {
     }
 
     fn has_commit(&self, commit: &str) -> Result<bool> {
+        #[cfg(feature = "gix")]
+        if let Ok(has_commit) = native::has_commit(&self.path, commit) {
+            return Ok(has_commit);
+        }
+
         Ok(self
             .git()
             .arg("cat-file")
@@ -390,6 +1034,60 @@ impl GitRepo {
             .success())
     }
 
+    /// Returns the contents of the blob at `spec` (e.g., `HEAD:.gitmodules`),
+    /// or `None` if it does not exist.
+    fn read_blob(&self, spec: &str) -> Result<Option<Vec<u8>>> {
+        #[cfg(feature = "gix")]
+        if let Ok(data) = native::read_blob(&self.path, spec) {
+            return Ok(data);
+        }
+
+        let output = self.git().arg("show").arg(spec).output()?;
+        Ok(output.status.success().then_some(output.stdout))
+    }
+
+    /// Returns the top-level directory of the working tree containing `path`.
+    fn toplevel(&self) -> Result<Utf8PathBuf> {
+        let output = self.git().arg("rev-parse").arg("--show-toplevel").output()?;
+        output
+            .status
+            .success()
+            .true_or(anyhow!("{} is not within a git working tree", self.path))?;
+        Ok(Utf8PathBuf::from(String::from_utf8(output.stdout)?.trim_end()))
+    }
+
+    fn has_ref(&self, reference: &str) -> Result<bool> {
+        Ok(self
+            .git()
+            .arg("show-ref")
+            .arg("--verify")
+            .arg("--quiet")
+            .arg(reference)
+            .status()?
+            .success())
+    }
+
+    /// Returns the commits that the refs matching `patterns` (as for `git
+    /// for-each-ref`) point to, with tags peeled.
+    fn ref_commits(&self, patterns: &[String]) -> Result<Vec<String>> {
+        let output = self
+            .git()
+            .arg("for-each-ref")
+            .arg("--format=%(if)%(*objectname)%(then)%(*objectname)%(else)%(objectname)%(end)")
+            .arg("--")
+            .args(patterns)
+            .output()?;
+        output
+            .status
+            .success()
+            .true_or(anyhow!("error listing refs of {}", self.path))?;
+
+        let mut commits = output.stdout.lines().collect::<Result<Vec<_>, _>>()?;
+        commits.sort();
+        commits.dedup();
+        Ok(commits)
+    }
+
     fn set_config(&self, key: &str, value: &str) -> Result<()> {
         self.git()
             .arg("config")
@@ -400,6 +1098,37 @@ impl GitRepo {
             .true_or(anyhow!("cannot set configuration value"))
     }
 
+    fn get_config(&self, key: &str) -> Result<Option<String>> {
+        let output = self.git().arg("config").arg("--get").arg(key).output()?;
+        if output.status.success() {
+            Ok(Some(String::from_utf8(output.stdout)?.trim_end().to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Returns the configuration of the repository, including the global
+    /// one, for looking up many keys at once.
+    fn config(&self) -> Result<gix_config::File<'static>> {
+        let dot_git = self.path.join(".git");
+        let git_dir = if dot_git.is_file() {
+            let gitfile =
+                std::fs::read_to_string(&dot_git).with_context(|| format!("reading {dot_git}"))?;
+            let git_dir = gitfile
+                .trim_end()
+                .strip_prefix("gitdir: ")
+                .ok_or_else(|| anyhow!("{dot_git} is not a gitfile"))?;
+            self.path.join(git_dir)
+        } else if dot_git.is_dir() {
+            dot_git
+        } else {
+            self.path.clone()
+        };
+
+        gix_config::File::from_git_dir(git_dir.into_std_path_buf())
+            .with_context(|| format!("reading the configuration of {}", self.path))
+    }
+
     fn checkout(&self, commit: &str) -> Result<()> {
         self.git()
             .arg("checkout")
@@ -409,18 +1138,42 @@ impl GitRepo {
             .true_or(anyhow!("error checking out commit"))
     }
 
+    /// Checks out `branch` and fast-forwards it to `origin`'s version.
+    fn checkout_branch(&self, branch: &str) -> Result<()> {
+        self.checkout(branch)?;
+        self.git()
+            .arg("merge")
+            .arg("--ff-only")
+            .arg(format!("refs/remotes/origin/{branch}"))
+            .status()?
+            .success()
+            .true_or(anyhow!("error fast-forwarding branch {branch}"))
+    }
+
     fn submodule_commits(&self) -> Result<HashMap<String, String>> {
-        let output = self.git().arg("submodule").arg("status").output()?;
+        #[cfg(feature = "gix")]
+        if let Ok(commits) = native::submodule_commits(&self.path) {
+            return Ok(commits);
+        }
+
+        // `git submodule status` appends a description once a submodule is
+        // checked out, so read the gitlinks from the index instead.
+        let output = self.git().arg("ls-files").arg("--stage").output()?;
 
         let res = output
             .stdout
             .lines()
-            .map(|line| line.unwrap())
-            .map(|line| {
-                // ` f47ce7b5fbbb3aa43d33d2be1f6cd3746b13d5bf some/path`
-                let commit = line[1..41].to_string();
-                let path = line[42..].to_string();
-                (path, commit)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter_map(|line| {
+                // `160000 f47ce7b5fbbb3aa43d33d2be1f6cd3746b13d5bf 0\tsome/path`
+                let (info, path) = line.split_once('\t')?;
+                let mut info = info.split(' ');
+                if info.next()? != "160000" {
+                    return None;
+                }
+                let commit = info.next()?.to_string();
+                Some((path.to_string(), commit))
             })
             .collect::<HashMap<String, String>>();
         Ok(res)
@@ -442,91 +1195,87 @@ impl GitRepo {
             .true_or(anyhow!("error setting up sparse checkout"))
     }
 
-    fn get_submodules(
-        &self,
-        filter: Option<Vec<String>>,
-    ) -> std::result::Result<Vec<SubmoduleSpec>, anyhow::Error> {
-        use gix_config::File;
-        let mut path = self.path.clone();
-        path.push(".gitmodules");
+    /// Returns the submodules of the working tree, with the commits
+    /// recorded in the index, and whether they are active according to the
+    /// repository's configuration.
+    fn submodules(&self) -> Result<Vec<SubmoduleSpec>> {
+        let path = self.path.join(".gitmodules");
 
         if !path.exists() {
             return Ok(Vec::new());
         }
 
-        let gitconfig = File::from_path_no_includes(path.into(), gix_config::Source::Api)?;
-        let gitmodules = gitconfig.sections_by_name("submodule");
-
-        if gitmodules.is_none() {
-            return Ok(Vec::new());
-        }
-
+        let data = std::fs::read(&path).with_context(|| format!("reading {path}"))?;
         let submodule_commits = self.submodule_commits()?;
 
-        let mut submodules = Vec::new();
-        for module in gitmodules.unwrap() {
-            let path = module.body().value("path");
-            let url = module.body().value("url");
-            let branch = module.body().value("branch").map(|b| b.to_string());
-
-            if path.is_none() || url.is_none() {
-                eprintln!("git-cache: submodule missing path or url");
-                continue;
-            }
-            let path = path.unwrap().into_owned().to_string();
-            let url = url.unwrap().into_owned().to_string();
-
-            let commit = submodule_commits.get(&path);
+        let mut submodules = parse_gitmodules(&data, &submodule_commits)?;
+        submodule::apply_config(&self.config()?, &mut submodules)?;
 
-            if commit.is_none() {
-                eprintln!("git-cache: could not find submodule commit for path `{path}`");
-            }
+        Ok(submodules)
+    }
 
-            if let Some(filter) = filter.as_ref() {
-                if !filter.contains(&path) {
-                    continue;
-                }
-            }
+    /// Returns the submodules as of `rev`, which works in bare repositories.
+    fn submodules_at(&self, rev: &str) -> Result<Vec<SubmoduleSpec>> {
+        let Some(data) = self.read_blob(&format!("{rev}:.gitmodules"))? else {
+            return Ok(Vec::new());
+        };
 
-            submodules.push(SubmoduleSpec::new(
-                path,
-                url,
-                commit.unwrap().clone(),
-                branch,
-            ));
+        let paths = parse_gitmodules(&data, &HashMap::new())?
+            .into_iter()
+            .map(|submodule| submodule.path)
+            .collect::<Vec<_>>();
+        if paths.is_empty() {
+            return Ok(Vec::new());
         }
 
-        Ok(submodules)
-    }
-
-    fn clone_submodule(
-        &self,
-        submodule: &SubmoduleSpec,
-        cache: &GitCache,
-        shallow_submodules: bool,
-        update: bool,
-    ) -> std::result::Result<(), anyhow::Error> {
-        let submodule_path = self.path.join(&submodule.path);
+        #[cfg(feature = "gix")]
+        if let Ok(commits) = native::submodule_commits_at(&self.path, rev, &paths) {
+            return parse_gitmodules(&data, &commits);
+        }
 
-        let mut cloner = cache.cloner();
+        let output = self
+            .git()
+            .arg("ls-tree")
+            .arg(rev)
+            .arg("--")
+            .args(&paths)
+            .output()?;
 
-        cloner
-            .repository_url(submodule.url.clone())
-            .target_path(Some(submodule_path))
-            .recurse_all_submodules(true)
-            .shallow_submodules(shallow_submodules)
-            .commit(Some(submodule.commit.clone()))
-            .update(update);
+        let submodule_commits = output
+            .stdout
+            .lines()
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .filter_map(|line| {
+                // `160000 commit f47ce7b5fbbb3aa43d33d2be1f6cd3746b13d5bf\tsome/path`
+                let (info, path) = line.split_once('\t')?;
+                let mut info = info.split(' ');
+                if info.next()? != "160000" {
+                    return None;
+                }
+                let commit = info.nth(1)?.to_string();
+                Some((path.to_string(), commit))
+            })
+            .collect::<HashMap<String, String>>();
 
-        // if let Some(branch) = submodule.branch {
-        //     cloner.extra_clone_args(Some(vec!["--branch".into(), branch]));
-        // }
+        parse_gitmodules(&data, &submodule_commits)
+    }
 
-        cloner.do_clone()?;
+    /// Returns the branch checked out, or `None` if `HEAD` is detached.
+    fn current_branch(&self) -> Result<Option<String>> {
+        let output = self
+            .git()
+            .arg("symbolic-ref")
+            .arg("--quiet")
+            .arg("--short")
+            .arg("HEAD")
+            .output()?;
 
-        self.init_submodule(&submodule.path)?;
+        if !output.status.success() {
+            return Ok(None);
+        }
 
-        Ok(())
+        Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()))
     }
 
     fn init_submodule(&self, path: &str) -> std::result::Result<(), anyhow::Error> {
@@ -546,176 +1295,393 @@ impl GitCacheRepo {
         let mut path = base_path.to_path_buf();
         path.push(Self::repo_path_from_url(url));
         Self {
-            repo: GitRepo { path },
+            repo: GitRepo {
+                path,
+                safe_directory: false,
+            },
             url: url.to_string(),
+            reference_paths: Vec::new(),
+            shared: false,
+            filter: None,
+            alternates: Vec::new(),
+            retry: RetryPolicy::default(),
+            backend: Arc::new(CliBackend),
         }
     }
 
-    fn mirror(&self) -> Result<bool> {
-        if !self.repo.is_initialized()? {
-            println!("git-cache: cloning {} into cache...", self.url);
-            std::fs::create_dir_all(&self.repo.path)?;
-            Command::new("git")
-                .arg("clone")
-                .arg("--mirror")
-                .arg("--")
-                .arg(&self.url)
-                .arg(&self.repo.path)
-                .status()?
-                .success()
-                .true_or(anyhow!("error mirroring repository"))?;
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn repo(&self) -> &GitRepo {
+        &self.repo
+    }
+
+    /// Returns where the mirror is (or would be) in the writable cache.
+    pub fn path(&self) -> &Utf8Path {
+        &self.repo.path
+    }
 
+    /// Returns `true` if the mirror exists.
+    pub fn is_initialized(&self) -> Result<bool> {
+        self.backend.is_initialized(&self.repo)
+    }
+
+    /// Returns where the mirror is, looking at the writable cache first and
+    /// then at the read-only tiers, in order.
+    pub fn cached_path(&self) -> Result<Option<&Utf8Path>> {
+        if self.is_initialized()? {
+            return Ok(Some(self.path()));
+        }
+        for reference in &self.reference_paths {
+            // read-only tiers are usually owned by someone else
+            let reference_repo = GitRepo {
+                path: reference.clone(),
+                safe_directory: true,
+            };
+            if self.backend.is_initialized(&reference_repo)? {
+                return Ok(Some(reference));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns `true` if bringing the mirror up to date (and making sure it
+    /// contains `commit`) probably needs the network.
+    ///
+    /// This is meant to be called without holding the lock, so it can only be
+    /// a hint: the mirror might change until the lock is taken.
+    fn may_fetch(&self, update: bool, commit: Option<&str>) -> Result<bool> {
+        if update || !self.is_initialized()? {
+            return Ok(true);
+        }
+        match commit {
+            Some(commit) => Ok(!self.has_commit(commit)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Creates the mirror if it doesn't exist yet.
+    ///
+    /// Returns `true` if the mirror was created.
+    fn mirror(&self) -> Result<bool> {
+        if !self.backend.is_initialized(&self.repo)? {
+            println!("git-cache: cloning {} into cache...", self.url);
+            self.backend.mirror(self)?;
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
+    /// Returns a `git` command for creating this mirror, honoring shared mode.
+    fn git_for_new_mirror(&self) -> Command {
+        let mut command = Command::new("git");
+        if self.shared {
+            command.arg("-c").arg("core.sharedRepository=group");
+        }
+        command
+    }
+
     fn update(&self) -> Result<()> {
+        self.backend.fetch(self)
+    }
+
+    /// Removes refs that no longer exist upstream.
+    fn prune(&self) -> Result<()> {
         self.repo
             .git()
             .arg("remote")
-            .arg("update")
+            .arg("prune")
+            .arg("origin")
             .status()?
             .success()
-            .true_or(anyhow!("error updating repository"))
+            .true_or(anyhow!("error pruning repository"))
     }
 
+    // Local paths and `file://` URLs map to `local/<absolute path>`.
+    //
     // # Panics
-    // This panics when called on an invalid or local URL, which shouldn't happen.
+    // This panics when called on a (non-`file://`) URL without a host, which
+    // shouldn't happen.
     fn repo_path_from_url(url: &str) -> Utf8PathBuf {
         let mut path = if let Ok(url) = url::Url::parse(url) {
-            assert!(url.scheme() != "file");
-            let (_, path) = url.path().split_at(1);
-            Utf8PathBuf::from(url.host_str().unwrap()).join(path)
+            if url.scheme() == "file" {
+                let (_, path) = url.path().split_at(1);
+                Utf8PathBuf::from("local").join(path)
+            } else {
+                let (_, path) = url.path().split_at(1);
+                Utf8PathBuf::from(url.host_str().unwrap()).join(path)
+            }
         } else if let Ok(scp_scheme) = ScpScheme::try_from(url) {
             Utf8PathBuf::from(scp_scheme.host).join(scp_scheme.path)
         } else {
-            unreachable!("shouldn't be here");
+            // local path, made absolute by `GitCache::repo()`
+            Utf8PathBuf::from("local").join(url.trim_start_matches('/'))
         };
         path.set_extension("git");
 
         path
     }
 
-    fn clone(&self, target_path: &str, pass_through_args: Option<&Vec<String>>) -> Result<()> {
-        direct_clone(self.repo.path.as_str(), target_path, pass_through_args)?;
+    fn clone(&self, target_path: &Utf8Path, pass_through_args: Option<&[String]>) -> Result<()> {
+        self.backend
+            .clone_from_cache(self, target_path, pass_through_args.unwrap_or_default())
+    }
 
-        Command::new("git")
-            .arg("-C")
-            .arg(target_path)
-            .arg("remote")
-            .arg("set-url")
-            .arg("origin")
-            .arg(&self.url)
+    /// Fetches branches and tags from this mirror into `target`, as if they
+    /// had been fetched from the upstream `remote`.
+    ///
+    /// With `prune`, remote-tracking branches that no longer exist in the
+    /// mirror are removed. Tags are never pruned.
+    fn fetch_into(&self, target: &GitRepo, remote: &str, prune: bool) -> Result<()> {
+        let mut fetch_cmd = target.git();
+        if self.repo.safe_directory {
+            fetch_cmd
+                .arg("-c")
+                .arg(format!("safe.directory={}", self.repo.path));
+        }
+        fetch_cmd.arg("fetch").arg("--no-recurse-submodules").arg("--tags");
+        if prune {
+            fetch_cmd.arg("--prune");
+        }
+
+        // Clones of partial mirrors fetch from the mirror as their promisor
+        // remote, so they stay partial. Others need the mirror to fetch what
+        // it doesn't have.
+        let promisor_remote = format!("remote.{}.promisor", partial::PROMISOR_REMOTE);
+        let source = if target.get_config(&promisor_remote)?.as_deref() == Some("true") {
+            partial::PROMISOR_REMOTE.to_string()
+        } else {
+            if partial::filter(&self.repo)?.is_some() {
+                fetch_cmd
+                    .arg("--upload-pack")
+                    .arg(partial::upload_pack(&self.repo));
+            }
+            self.repo.path.to_string()
+        };
+
+        fetch_cmd
+            .arg("--")
+            .arg(source)
+            .arg(format!("+refs/heads/*:refs/remotes/{remote}/*"))
             .status()?
             .success()
-            .true_or(anyhow!("error updating remote url"))?;
-        Ok(())
+            .true_or(anyhow!("error fetching from cache"))
     }
 
     pub fn target_path(&self, target_path: Option<&Utf8PathBuf>) -> Result<Utf8PathBuf> {
         target_path_from_url_maybe(&self.url, target_path)
     }
 
-    // fn is_initialized(&self) -> std::result::Result<bool, anyhow::Error> {
-    //     self.repo.is_initialized()
-    // }
-
     fn has_commit(&self, commit: &str) -> std::result::Result<bool, anyhow::Error> {
-        self.repo.has_commit(commit)
+        self.backend.has_commit(&self.repo, commit)
+    }
+
+    fn fetch_commit(&self, commit: &str) -> Result<()> {
+        self.backend.fetch_commit(self, commit)
     }
 
     fn lockfile(&self) -> Result<fd_lock::RwLock<File>> {
         let base_path = self.repo.path.parent().unwrap();
-        std::fs::create_dir_all(&base_path)
+        shared::create_dir_all(base_path, self.shared)
             .with_context(|| format!("creating repo base path '{base_path}'"))?;
 
         let lock_path = self.repo.path.with_extension("git.lock");
-        Ok(fd_lock::RwLock::new(
-            std::fs::File::create(&lock_path)
-                .with_context(|| format!("creating lock file \"{lock_path}\""))?,
-        ))
+        Ok(fd_lock::RwLock::new(shared::create_lock_file(
+            &lock_path,
+            self.shared,
+        )?))
     }
 
-    fn get_submodules(&self) -> std::result::Result<Vec<String>, anyhow::Error> {
-        let output = self
-            .repo
-            .git()
-            .arg("show")
-            .arg("HEAD:.gitmodules")
-            .output()?;
+    /// Returns the URLs of the submodules at any of `revs`, with the commits
+    /// they are pinned to there.
+    fn get_submodules(&self, revs: &[String]) -> Result<BTreeSet<(String, String)>> {
+        let mut submodules = BTreeSet::new();
+        for rev in revs {
+            submodules.extend(
+                self.backend
+                    .list_submodules(&self.repo, Some(rev))?
+                    .into_iter()
+                    .filter(SubmoduleSpec::is_updated)
+                    .map(|submodule| {
+                        let url = resolve_submodule_url(&self.url, &submodule.url);
+                        (url, submodule.commit)
+                    }),
+            );
+        }
+        Ok(submodules)
+    }
 
-        let data = output.stdout;
-        let gitconfig =
-            gix_config::File::from_bytes_no_includes(&data, Metadata::api(), Options::default())?;
-        let gitmodules = gitconfig.sections_by_name("submodule");
+    fn ref_commits(&self, patterns: &[String]) -> Result<Vec<String>> {
+        self.backend.list_refs(&self.repo, patterns)
+    }
+}
 
-        if let Some(gitmodules) = gitmodules {
-            Ok(gitmodules
-                .filter_map(|submodule| submodule.body().value("url").map(|cow| cow.to_string()))
-                .collect())
-        } else {
-            return Ok(vec![]);
+/// Parses `.gitmodules` data, taking the submodule commits from
+/// `submodule_commits` (by path).
+///
+/// Submodules without a commit get an empty `commit`, callers that need it
+/// have to deal with that.
+fn parse_gitmodules(
+    data: &[u8],
+    submodule_commits: &HashMap<String, String>,
+) -> Result<Vec<SubmoduleSpec>> {
+    let gitconfig =
+        gix_config::File::from_bytes_no_includes(data, Metadata::api(), Options::default())?;
+
+    let Some(gitmodules) = gitconfig.sections_by_name("submodule") else {
+        return Ok(Vec::new());
+    };
+
+    let mut submodules = Vec::new();
+    for module in gitmodules {
+        let name = module.header().subsection_name().map(|name| name.to_string());
+        let path = module.body().value("path");
+        let url = module.body().value("url");
+        let branch = module.body().value("branch").map(|b| b.to_string());
+        let update = module.body().value("update").map(|u| u.to_string());
+
+        let (Some(path), Some(url)) = (path, url) else {
+            eprintln!("git-cache: submodule missing path or url");
+            continue;
+        };
+        let path = path.into_owned().to_string();
+        let url = url.into_owned().to_string();
+
+        let commit = submodule_commits.get(&path).cloned().unwrap_or_default();
+
+        let mut submodule = SubmoduleSpec::new(path, url, commit, branch);
+        if let Some(name) = name {
+            submodule.name = name;
         }
+        submodule.update = update;
+        submodules.push(submodule);
     }
-}
 
-fn direct_clone(
-    repo: &str,
-    target_path: &str,
-    pass_through_args: Option<&Vec<String>>,
-) -> Result<(), Error> {
-    let mut clone_cmd = Command::new("git");
-    clone_cmd.arg("clone").arg("--shared");
-    if let Some(args) = pass_through_args {
-        clone_cmd.args(args);
-    }
-    clone_cmd
-        .arg("--")
-        .arg(repo)
-        .arg(target_path)
-        .status()?
-        .success()
-        .true_or(anyhow!("cloning failed"))?;
-    Ok(())
+    Ok(submodules)
 }
 
-fn prefetch_url(
-    repository_url: &str,
-    cache_base_dir: &Utf8Path,
+/// Prefetches the mirror of `entries`, which all refer to the same one, with
+/// `update` and `recurse` applying unless an entry overrides them.
+///
+/// Returns the result for each entry, or an error if the mirror couldn't be
+/// prefetched at all.
+fn prefetch_mirror(
+    entries: &[PrefetchEntry],
+    cache: &GitCache,
     update: bool,
     recurse: bool,
+    submodule_refs: &[String],
     sender: &Sender<Prefetch>,
-) -> Result<(), Error> {
-    scopeguard::defer! {
-        let _ = sender.send(Prefetch::Done);
+) -> Result<Vec<Result<(), Error>>, Error> {
+    let repository_url = entries[0].url.as_str();
+    let update = entries.iter().any(|entry| entry.update.unwrap_or(update));
+
+    if !cache.is_cacheable(repository_url) {
+        bail!("can only cache remote repositories, '{repository_url}' is local");
     }
 
-    let cache_repo = GitCacheRepo::new(cache_base_dir, repository_url);
+    let cache_repo = cache.repo(repository_url);
 
     let mut lock = cache_repo.lockfile()?;
-    {
+    let results = {
         let _lock = lock.write()?;
         if !cache_repo.mirror()? {
-            if update {
+            let mut try_update = update;
+            for commit in entries.iter().filter_map(|entry| entry.commit.as_deref()) {
+                try_update = try_update || !cache_repo.has_commit(commit)?;
+            }
+            if try_update {
                 println!("git-cache: updating cache for {repository_url}...");
                 cache_repo.update()?;
             }
         }
-    }
 
-    if recurse {
+        entries
+            .iter()
+            .map(|entry| match &entry.commit {
+                Some(commit) => ensure_commit(&cache_repo, commit),
+                None => Ok(()),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let mut submodules = Vec::new();
+    {
         let _lock = lock.read()?;
-        for url in cache_repo.get_submodules()? {
-            println!("git-cache: {repository_url} getting submodule: {url}");
-            let _ = sender.send(Prefetch::Url(url));
+        for (entry, result) in entries.iter().zip(&results) {
+            if result.is_ok() && entry.recurse.unwrap_or(recurse) {
+                submodules.extend(submodule_entries(&cache_repo, entry, submodule_refs)?);
+            }
+        }
+    }
+    if !submodules.is_empty() {
+        for submodule in &submodules {
+            println!("git-cache: {repository_url} getting submodule: {submodule}");
         }
+        let _ = sender.send(Prefetch::Submodules(repository_url.to_string(), submodules));
     }
 
+    Ok(results)
+}
+
+/// Makes sure the mirror contains `commit`, which might need fetching it by
+/// its id.
+fn ensure_commit(cache_repo: &GitCacheRepo, commit: &str) -> Result<()> {
+    let repository_url = &cache_repo.url;
+    // the commit might not be reachable from any (visible) ref
+    if !cache_repo.has_commit(commit)? && is_commit_id(commit) {
+        println!("git-cache: fetching {commit} into cache for {repository_url}...");
+        if let Err(e) = cache_repo.fetch_commit(commit) {
+            println!("git-cache: {e:#}");
+        }
+    }
+    if !cache_repo.has_commit(commit)? {
+        bail!("{repository_url} does not contain commit {commit}");
+    }
     Ok(())
 }
 
+/// Returns the submodules of `entry` (mirrored in `cache_repo`) to prefetch
+/// next.
+///
+/// With `submodule_refs`, submodules are pinned to their commits, and those
+/// commits are where their own submodules are looked up.
+fn submodule_entries(
+    cache_repo: &GitCacheRepo,
+    entry: &PrefetchEntry,
+    submodule_refs: &[String],
+) -> Result<Vec<PrefetchEntry>> {
+    let pinned = !submodule_refs.is_empty();
+    let revs = match &entry.commit {
+        Some(commit) if pinned => vec![commit.clone()],
+        _ if pinned => {
+            let mut revs = vec!["HEAD".to_string()];
+            revs.extend(cache_repo.ref_commits(submodule_refs)?);
+            revs
+        }
+        _ => vec!["HEAD".to_string()],
+    };
+
+    Ok(cache_repo
+        .get_submodules(&revs)?
+        .into_iter()
+        // submodules inherit the options of their superproject
+        .map(|(url, commit)| PrefetchEntry {
+            url,
+            commit: (pinned && !commit.is_empty()).then_some(commit),
+            ..entry.clone()
+        })
+        .collect())
+}
+
+/// Returns `true` if `rev` looks like a commit id, possibly abbreviated
+/// (SHA-1 or SHA-256), rather than a ref name.
+pub(crate) fn is_commit_id(rev: &str) -> bool {
+    (7..=64).contains(&rev.len()) && rev.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 fn target_path_from_url_maybe(
     url: &str,
     target_path: Option<&Utf8PathBuf>,
@@ -739,10 +1705,12 @@ pub fn clap_git_cache_dir_arg() -> Arg {
     Arg::new("git_cache_dir")
         .short('c')
         .long("cache-dir")
-        .help("git cache base directory")
+        .help("git cache directory, optionally followed by read-only tiers (separated like PATH)")
         .required(false)
         .default_value("~/.gitcache")
+        // the first value is the writable cache, as for a single directory
         .value_parser(clap::value_parser!(Utf8PathBuf))
+        .value_delimiter(if cfg!(windows) { ';' } else { ':' })
         .value_hint(ValueHint::DirPath)
         .env("GIT_CACHE_DIR")
         .num_args(1)
@@ -793,7 +1761,22 @@ pub fn clap_clone_command(name: &'static str) -> clap::Command {
                 .action(ArgAction::Append)
                 .num_args(0..=1)
                 .require_equals(true)
-                .help("recursively clone submodules"),
+                .help("recursively clone submodules, optionally only those matching <pathspec> (path below the top-level repository)"),
+        )
+        .arg(
+            Arg::new("exclude-submodule")
+                .long("exclude-submodule")
+                .value_name("glob")
+                .action(ArgAction::Append)
+                .help("don't clone submodules (and their submodules) matching <glob> (path below the top-level repository)"),
+        )
+        .arg(
+            Arg::new("submodule-depth")
+                .long("submodule-depth")
+                .value_name("N")
+                .num_args(1)
+                .value_parser(clap::value_parser!(usize))
+                .help("clone at most N levels of nested submodules"),
         )
         .arg(
             Arg::new("shallow-submodules")
@@ -809,6 +1792,20 @@ pub fn clap_clone_command(name: &'static str) -> clap::Command {
                 .overrides_with("shallow-submodules")
                 .help("don't shallow-clone submodules"),
         )
+        .arg(
+            Arg::new("remote-submodules")
+                .long("remote-submodules")
+                .action(ArgAction::SetTrue)
+                .overrides_with("no-remote-submodules")
+                .help("check out the tip of the submodules' branches instead of the recorded commits"),
+        )
+        .arg(
+            Arg::new("no-remote-submodules")
+                .long("no-remote-submodules")
+                .action(ArgAction::SetTrue)
+                .overrides_with("remote-submodules")
+                .help("check out the recorded commits of submodules (default)"),
+        )
         .arg(
             Arg::new("jobs")
                 .long("jobs")
@@ -817,6 +1814,15 @@ pub fn clap_clone_command(name: &'static str) -> clap::Command {
                 .num_args(1)
                 .value_parser(clap::value_parser!(usize)),
         )
+        .arg(clap_mirror_filter_arg())
+        .arg(
+            Arg::new("also-filter-submodules")
+                .long("also-filter-submodules")
+                .action(ArgAction::SetTrue)
+                .requires("filter")
+                .help("create submodule mirrors as partial clones, too"),
+        )
+        .arg(clap_dry_run_arg())
         .args(pass_through_args())
         .after_help(
             "These regular \"git clone\" options are passed through:\n
@@ -826,8 +1832,7 @@ pub fn clap_clone_command(name: &'static str) -> clap::Command {
         [--dissociate] [--separate-git-dir <git-dir>]
         [--depth <depth>] [--[no-]single-branch] [--no-tags]
         [--recurse-submodules[=<pathspec>]] [--[no-]shallow-submodules]
-        [--[no-]remote-submodules] [--jobs <n>] [--sparse] [--[no-]reject-shallow]
-        [--filter=<filter> [--also-filter-submodules]]",
+        [--jobs <n>] [--sparse] [--[no-]reject-shallow]",
         )
 }
 
@@ -837,10 +1842,37 @@ pub fn clap_prefetch_command(name: &'static str) -> clap::Command {
         .about("pre-fetch repositories into the cache")
         .arg(
             Arg::new("repositories")
-                .help("repositories to prefetch")
-                .required(true)
+                .help("repositories to prefetch, as `<url>[@<rev>]`")
+                .required_unless_present_any(["from-file", "cargo-lock"])
                 .num_args(1..),
         )
+        .arg(
+            Arg::new("from-file")
+                .long("from-file")
+                .value_name("PATH")
+                .help("read repositories from PATH (`-` for stdin), one per line, optionally followed by `[no-]update` or `[no-]recurse`")
+                .num_args(1)
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("cargo-lock")
+                .long("cargo-lock")
+                .value_name("PATH")
+                .help("prefetch the git dependencies locked in a Cargo.lock, including their commits")
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("cargo-config")
+                .long("cargo-config")
+                .value_name("PATH")
+                .help("write .cargo/config.toml settings redirecting the Cargo.lock git dependencies to the cache to PATH")
+                .requires("cargo-lock")
+                .num_args(1)
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::FilePath),
+        )
         .arg(
             Arg::new("update")
                 .short('U')
@@ -855,6 +1887,19 @@ pub fn clap_prefetch_command(name: &'static str) -> clap::Command {
                 .action(ArgAction::SetTrue)
                 .help("recursively prefetch submodules"),
         )
+        .arg(
+            Arg::new("submodule-refs")
+                .long("submodule-refs")
+                .value_name("PATTERN")
+                .help("also prefetch the submodules of refs matching PATTERN (e.g. `refs/tags/v*`), with the commits they are pinned to (implies --recurse-submodules)")
+                .action(ArgAction::Append),
+        )
+        .arg(
+            Arg::new("all-refs")
+                .long("all-refs")
+                .action(ArgAction::SetTrue)
+                .help("like `--submodule-refs refs/heads --submodule-refs refs/tags`"),
+        )
         .arg(
             Arg::new("jobs")
                 .long("jobs")
@@ -863,6 +1908,235 @@ pub fn clap_prefetch_command(name: &'static str) -> clap::Command {
                 .num_args(1)
                 .value_parser(clap::value_parser!(usize)),
         )
+        .arg(
+            Arg::new("show-graph")
+                .long("show-graph")
+                .action(ArgAction::SetTrue)
+                .help("show the submodules discovered, as a tree per repository"),
+        )
+        .arg(
+            Arg::new("keep-going")
+                .long("keep-going")
+                .short('k')
+                .action(ArgAction::SetTrue)
+                .help("continue with the remaining repositories after a failure"),
+        )
+        .arg(clap_mirror_filter_arg())
+        .arg(clap_dry_run_arg())
+}
+
+fn clap_mirror_filter_arg() -> Arg {
+    Arg::new("filter")
+        .long("filter")
+        .value_name("filter-spec")
+        .help("create new cache mirrors as partial clones (e.g., blob:none)")
+        .num_args(1)
+}
+
+fn clap_dry_run_arg() -> Arg {
+    Arg::new("dry-run")
+        .long("dry-run")
+        .action(ArgAction::SetTrue)
+        .help("only show what would be done, based on what's in the cache")
+}
+
+pub fn clap_export_command(name: &'static str) -> clap::Command {
+    use clap::Command;
+    Command::new(name)
+        .about("export cached repositories as git bundles")
+        .arg(
+            Arg::new("repositories")
+                .help("repositories to export")
+                .required(true)
+                .num_args(1..),
+        )
+        .arg(
+            Arg::new("bundle-dir")
+                .long("bundle-dir")
+                .value_name("DIR")
+                .help("directory to write the bundles and their manifest to")
+                .required(true)
+                .num_args(1)
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::DirPath),
+        )
+}
+
+pub fn clap_import_command(name: &'static str) -> clap::Command {
+    use clap::Command;
+    Command::new(name)
+        .about("import git bundles created by \"export\" into the cache")
+        .arg(
+            Arg::new("bundle-dir")
+                .help("directory containing the bundles and their manifest")
+                .required(true)
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::DirPath),
+        )
+}
+
+pub fn clap_git_cache_shared_arg() -> Arg {
+    Arg::new("shared_cache")
+        .long("shared-cache")
+        .help("cache directory is shared by the members of a Unix group")
+        .action(ArgAction::SetTrue)
+        .env("GIT_CACHE_SHARED")
+}
+
+pub fn clap_git_cache_local_repos_arg() -> Arg {
+    Arg::new("cache_local_repos")
+        .long("cache-local-repos")
+        .help("also cache repositories given by local path or file:// URL")
+        .action(ArgAction::SetTrue)
+        .env("GIT_CACHE_LOCAL_REPOS")
+        .hide(true)
+}
+
+pub fn clap_git_cache_retry_args() -> Vec<Arg> {
+    vec![
+        Arg::new("retries")
+            .long("retries")
+            .value_name("N")
+            .help("retry creating or updating mirrors up to N times on transient network errors")
+            .default_value("0")
+            .env("GIT_CACHE_RETRIES")
+            .value_parser(clap::value_parser!(u32)),
+        Arg::new("retry_backoff")
+            .long("retry-backoff")
+            .value_name("SECONDS")
+            .help("delay before the first retry, doubling with every further retry")
+            .default_value("1")
+            .env("GIT_CACHE_RETRY_BACKOFF")
+            .value_parser(clap::value_parser!(u64)),
+        Arg::new("timeout")
+            .long("timeout")
+            .value_name("SECONDS")
+            .help("abort (and maybe retry) attempts at creating or updating mirrors after SECONDS")
+            .env("GIT_CACHE_TIMEOUT")
+            .value_parser(clap::value_parser!(u64)),
+    ]
+}
+
+pub fn clap_sync_command(name: &'static str) -> clap::Command {
+    use clap::Command;
+    Command::new(name)
+        .about("clone or update all repositories listed in a manifest")
+        .arg(
+            Arg::new("manifest")
+                .help("manifest file (TOML, or YAML if ending in .yml/.yaml)")
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("west")
+                .long("west")
+                .value_name("FILE")
+                .help("use a Zephyr west manifest (west.yml)")
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::FilePath),
+        )
+        .arg(
+            Arg::new("repo")
+                .long("repo")
+                .value_name("FILE")
+                .help("use an Android repo manifest (default.xml)")
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::FilePath),
+        )
+        .group(
+            clap::ArgGroup::new("manifest-source")
+                .args(["manifest", "west", "repo"])
+                .required(true),
+        )
+        .arg(
+            Arg::new("manifest-url")
+                .long("manifest-url")
+                .value_name("URL")
+                .requires("repo")
+                .help("URL of the repo manifest repository, for relative remote URLs"),
+        )
+        .arg(
+            Arg::new("workspace")
+                .long("workspace")
+                .short('w')
+                .value_name("DIR")
+                .help("directory that manifest paths are relative to")
+                .default_value(".")
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::DirPath),
+        )
+        .arg(
+            Arg::new("update")
+                .short('U')
+                .long("update")
+                .action(ArgAction::SetTrue)
+                .help("force update of cached repo(s)"),
+        )
+        .arg(
+            Arg::new("jobs")
+                .long("jobs")
+                .short('j')
+                .help("The number of repositories synced at the same time.")
+                .num_args(1)
+                .value_parser(clap::value_parser!(usize)),
+        )
+}
+
+pub fn clap_fetch_command(name: &'static str) -> clap::Command {
+    use clap::Command;
+    Command::new(name)
+        .about("fetch into an existing clone through the cache")
+        .arg(
+            Arg::new("path")
+                .help("path of the clone")
+                .default_value(".")
+                .value_parser(clap::value_parser!(Utf8PathBuf))
+                .value_hint(ValueHint::DirPath),
+        )
+        .arg(
+            Arg::new("remote")
+                .long("remote")
+                .value_name("NAME")
+                .default_value("origin")
+                .help("remote whose URL is looked up in the cache"),
+        )
+        .arg(
+            Arg::new("prune")
+                .short('p')
+                .long("prune")
+                .action(ArgAction::SetTrue)
+                .help("remove remote-tracking branches that no longer exist upstream"),
+        )
+}
+
+pub fn clap_doctor_command(name: &'static str) -> clap::Command {
+    use clap::Command;
+    Command::new(name).about("check the cache for problems")
+}
+
+pub fn clap_path_command(name: &'static str) -> clap::Command {
+    use clap::Command;
+    Command::new(name)
+        .about("print where repositories are cached, or would be")
+        .visible_alias("which")
+        .arg(
+            Arg::new("repositories")
+                .help("repository URLs")
+                .required(true)
+                .num_args(1..),
+        )
+        .arg(
+            Arg::new("check")
+                .long("check")
+                .action(ArgAction::SetTrue)
+                .help("exit with an error if a repository is not cached in any tier"),
+        )
+        .arg(
+            Arg::new("status")
+                .long("status")
+                .action(ArgAction::SetTrue)
+                .help("follow each path by a tab and `cached` or `not cached`"),
+        )
 }
 
 fn pass_through_args() -> Vec<Arg> {
@@ -919,23 +2193,21 @@ fn pass_through_args() -> Vec<Arg> {
                 .short(short)
                 .long(long)
                 .num_args(1)
+                .action(ArgAction::Append)
                 .hide(true),
         );
     }
 
     // long w/o arg
     for id in [
-        "also-filter-submodules",
         "bare",
         "dissociate",
         "mirror",
         "no-hardlinks",
         "no-reject-shallow",
-        "no-remote-submodules",
         "no-single-branch",
         "no-tags",
         "reject-shallow",
-        "remote-submodules",
         "single-branch",
     ]
     .into_iter()
@@ -947,7 +2219,6 @@ fn pass_through_args() -> Vec<Arg> {
     for id in [
         "bundle-uri",
         "depth",
-        "filter",
         "reference",
         "reference-if-able",
         "separate-git-dir",
@@ -957,7 +2228,13 @@ fn pass_through_args() -> Vec<Arg> {
     ]
     .into_iter()
     {
-        args.push(Arg::new(id).long(id).num_args(1).hide(true));
+        args.push(
+            Arg::new(id)
+                .long(id)
+                .num_args(1)
+                .action(ArgAction::Append)
+                .hide(true),
+        );
     }
 
     args
@@ -972,17 +2249,14 @@ fn get_pass_through_args(matches: &ArgMatches) -> Vec<String> {
         "quiet",
         "shared",
         "verbose",
-        "also-filter-submodules",
         "bare",
         "dissociate",
         "mirror",
         "no-hardlinks",
         "no-reject-shallow",
-        "no-remote-submodules",
         "no-single-branch",
         "no-tags",
         "reject-shallow",
-        "remote-submodules",
         "single-branch",
         "sparse",
     ]
@@ -999,7 +2273,6 @@ fn get_pass_through_args(matches: &ArgMatches) -> Vec<String> {
         "bundle-uri",
         "config",
         "depth",
-        "filter",
         "origin",
         "reference",
         "reference-if-able",
@@ -1046,22 +2319,575 @@ impl TrueOr for bool {
     }
 }
 
-#[derive(Debug, Clone)]
-struct SubmoduleSpec {
-    path: String,
-    url: String,
-    #[allow(dead_code)]
-    branch: Option<String>,
-    commit: String,
+/// How submodules get cloned, passed down the recursion tree.
+#[derive(Debug, Clone, Default)]
+struct SubmoduleOptions {
+    filter: SubmoduleFilter,
+    shallow: bool,
+    /// check out the tip of the submodule's branch
+    remote: bool,
+    update: bool,
+    /// create submodule mirrors with the cache's mirror filter
+    also_filter: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubmoduleSpec {
+    /// the name of the submodule's section in `.gitmodules`
+    pub name: String,
+    pub path: String,
+    pub url: String,
+    /// `submodule.<name>.branch`, "." meaning the superproject's branch
+    pub branch: Option<String>,
+    /// the commit recorded in the superproject (empty if unknown)
+    pub commit: String,
+    /// `submodule.<name>.update` (e.g., "none")
+    pub update: Option<String>,
+    /// whether the submodule is active, see `git help gitsubmodules`
+    pub active: bool,
 }
 
 impl SubmoduleSpec {
     pub fn new(path: String, url: String, commit: String, branch: Option<String>) -> Self {
         Self {
+            name: path.clone(),
             path,
             url,
             commit,
             branch,
+            update: None,
+            active: true,
+        }
+    }
+
+    /// Returns `true` if the submodule should be cloned, ignoring whether it
+    /// is active.
+    pub fn is_updated(&self) -> bool {
+        self.update.as_deref() != Some("none")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::fake::{FakeBackend, FakeRepo};
+
+    const SUPER: &str = "https://example.com/super.git";
+    const SUB: &str = "https://example.com/sub.git";
+    const NESTED: &str = "https://example.com/nested.git";
+
+    struct Fixture {
+        _dir: tempfile::TempDir,
+        base: Utf8PathBuf,
+        backend: Arc<FakeBackend>,
+        cache: GitCache,
+    }
+
+    fn fixture() -> Fixture {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Utf8PathBuf::from_path_buf(dir.path().to_path_buf()).unwrap();
+        let backend = Arc::new(FakeBackend::default());
+        let cache = GitCache::new(base.join("cache"))
+            .unwrap()
+            .with_backend(backend.clone());
+        Fixture {
+            _dir: dir,
+            base,
+            backend,
+            cache,
+        }
+    }
+
+    impl Fixture {
+        fn clone(&self, url: &str, target: &str, commit: Option<&str>) -> Result<Utf8PathBuf> {
+            let target_path = self.base.join(target);
+            self.cache
+                .cloner()
+                .repository_url(url.to_string())
+                .target_path(Some(target_path.clone()))
+                .commit(commit.map(str::to_string))
+                .recurse_all_submodules(true)
+                .do_clone()?;
+            Ok(target_path)
+        }
+    }
+
+    #[test]
+    fn relative_submodule_urls() {
+        for (base, url, expected) in [
+            ("https://host/a/super.git", "../sub.git", "https://host/a/sub.git"),
+            ("https://host/a/super.git/", "./sub", "https://host/a/super.git/sub"),
+            ("https://host/a/super", "../../b/sub", "https://host/b/sub"),
+            ("git@host:a/super.git", "../sub.git", "git@host:a/sub.git"),
+            ("git@host:super.git", "../sub.git", "git@host:sub.git"),
+            ("/srv/git/super.git", "../sub.git", "/srv/git/sub.git"),
+            ("https://host/super.git", "https://other/sub.git", "https://other/sub.git"),
+        ] {
+            assert_eq!(resolve_submodule_url(base, url), expected, "{base} + {url}");
         }
     }
+
+    #[test]
+    fn mirror_is_created_once() {
+        let f = fixture();
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1"]));
+
+        f.clone(SUPER, "a", None).unwrap();
+        f.clone(SUPER, "b", None).unwrap();
+
+        assert_eq!(f.backend.count(&format!("mirror {SUPER}")), 1);
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 0);
+        assert!(f.base.join("cache/example.com/super.git.lock").exists());
+    }
+
+    #[test]
+    fn update_only_when_commit_missing() {
+        let f = fixture();
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1"]));
+        f.clone(SUPER, "a", None).unwrap();
+
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1", "c2"]));
+        f.clone(SUPER, "b", Some("c1")).unwrap();
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 0);
+
+        let target = f.clone(SUPER, "c", Some("c2")).unwrap();
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 1);
+        assert_eq!(f.backend.repo(&target).unwrap().head.as_deref(), Some("c2"));
+    }
+
+    #[test]
+    fn missing_commit_fails_after_update() {
+        let f = fixture();
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1"]));
+        f.clone(SUPER, "a", None).unwrap();
+
+        let err = f.clone(SUPER, "b", Some("c3")).unwrap_err();
+        assert!(err.to_string().contains("does not contain commit c3"));
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 1);
+        assert!(!f.base.join("b").exists());
+    }
+
+    #[test]
+    fn submodules_are_cloned_recursively() {
+        let f = fixture();
+        f.backend.set_upstream(
+            SUPER,
+            FakeRepo::new(&["c1"]).with_submodule("lib/sub", SUB, "s1"),
+        );
+        f.backend.set_upstream(
+            SUB,
+            FakeRepo::new(&["s1", "s2"]).with_submodule("nested", NESTED, "n1"),
+        );
+        f.backend.set_upstream(NESTED, FakeRepo::new(&["n1"]));
+
+        let target = f.clone(SUPER, "a", None).unwrap();
+
+        let sub = f.backend.repo(&target.join("lib/sub")).unwrap();
+        assert_eq!(sub.head.as_deref(), Some("s1"));
+        assert_eq!(sub.initialized_submodules, ["nested"]);
+
+        let nested = f.backend.repo(&target.join("lib/sub/nested")).unwrap();
+        assert_eq!(nested.head.as_deref(), Some("n1"));
+
+        let superproject = f.backend.repo(&target).unwrap();
+        assert_eq!(superproject.initialized_submodules, ["lib/sub"]);
+        for url in [SUPER, SUB, NESTED] {
+            assert_eq!(f.backend.count(&format!("mirror {url}")), 1);
+        }
+    }
+
+    #[test]
+    fn inactive_submodules_are_skipped() {
+        let f = fixture();
+        let mut inactive = SubmoduleSpec::new("inactive".into(), SUB.into(), "s1".into(), None);
+        inactive.active = false;
+        let mut not_updated = SubmoduleSpec::new("none".into(), SUB.into(), "s1".into(), None);
+        not_updated.update = Some("none".into());
+        let mut superproject = FakeRepo::new(&["c1"]).with_submodule("nested", NESTED, "n1");
+        superproject.submodules.extend([inactive, not_updated]);
+        f.backend.set_upstream(SUPER, superproject);
+        f.backend.set_upstream(NESTED, FakeRepo::new(&["n1"]));
+
+        let target = f.clone(SUPER, "a", None).unwrap();
+
+        let superproject = f.backend.repo(&target).unwrap();
+        assert_eq!(superproject.initialized_submodules, ["nested"]);
+        assert_eq!(f.backend.count(&format!("mirror {SUB}")), 0);
+    }
+
+    #[test]
+    fn submodule_filters_apply_to_nested_submodules() {
+        let f = fixture();
+        f.backend.set_upstream(
+            SUPER,
+            FakeRepo::new(&["c1"]).with_submodule("lib", SUB, "s1"),
+        );
+        f.backend.set_upstream(
+            SUB,
+            FakeRepo::new(&["s1"]).with_submodule("nested", NESTED, "n1"),
+        );
+        f.backend.set_upstream(NESTED, FakeRepo::new(&["n1"]));
+
+        let clone = |target: &str, exclude: &[&str], depth: Option<usize>| {
+            let target_path = f.base.join(target);
+            f.cache
+                .cloner()
+                .repository_url(SUPER.to_string())
+                .target_path(Some(target_path.clone()))
+                .recurse_all_submodules(true)
+                .exclude_submodules(exclude.iter().map(|s| s.to_string()).collect())
+                .submodule_depth(depth)
+                .do_clone()
+                .unwrap();
+            f.backend.repo(&target_path.join("lib")).unwrap()
+        };
+
+        let sub = clone("excluded", &["lib/nest*"], None);
+        assert!(sub.initialized_submodules.is_empty());
+        let sub = clone("depth", &[], Some(1));
+        assert!(sub.initialized_submodules.is_empty());
+        assert_eq!(f.backend.count(&format!("mirror {NESTED}")), 0);
+
+        let sub = clone("all", &["nested"], Some(2));
+        assert_eq!(sub.initialized_submodules, ["nested"]);
+    }
+
+    #[test]
+    fn prefetch_recurses_into_submodules() {
+        let f = fixture();
+        f.backend.set_upstream(
+            SUPER,
+            FakeRepo::new(&["c1"]).with_submodule("sub", SUB, "s1"),
+        );
+        f.backend.set_upstream(
+            SUB,
+            FakeRepo::new(&["s1"]).with_submodule("nested", NESTED, "n1"),
+        );
+        f.backend.set_upstream(NESTED, FakeRepo::new(&["n1"]));
+
+        f.cache
+            .prefetcher()
+            .repository_urls(vec![SUPER.to_string()])
+            .recurse_all_submodules(true)
+            .jobs(Some(2))
+            .do_prefetch()
+            .unwrap();
+
+        for url in [SUPER, SUB, NESTED] {
+            assert_eq!(f.backend.count(&format!("mirror {url}")), 1);
+        }
+        assert!(f
+            .backend
+            .calls()
+            .iter()
+            .all(|call| call.starts_with("mirror ")));
+    }
+
+    #[test]
+    fn prefetch_updates_existing_mirrors_only_on_request() {
+        let f = fixture();
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1"]));
+
+        for update in [false, false, true] {
+            f.cache
+                .prefetcher()
+                .repository_urls(vec![SUPER.to_string()])
+                .update(update)
+                .do_prefetch()
+                .unwrap();
+        }
+
+        assert_eq!(f.backend.count(&format!("mirror {SUPER}")), 1);
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 1);
+    }
+
+    #[test]
+    fn plans_change_nothing() {
+        use crate::plan::Action;
+
+        let f = fixture();
+        f.backend.set_upstream(
+            SUPER,
+            FakeRepo::new(&["c1"])
+                .with_submodule("sub", SUB, "s1")
+                .with_submodule("docs", NESTED, "n1"),
+        );
+        f.backend.set_upstream(SUB, FakeRepo::new(&["s1"]));
+        let mirror = |url: &str| f.cache.repo(url).repo().path().to_path_buf();
+        let target = f.base.join("a");
+
+        let plan = |commit: Option<&str>| {
+            f.cache
+                .cloner()
+                .repository_url(SUPER.to_string())
+                .target_path(Some(target.clone()))
+                .commit(commit.map(str::to_string))
+                .recurse_all_submodules(true)
+                .exclude_submodules(vec!["docs".into()])
+                .plan()
+                .unwrap()
+        };
+
+        assert_eq!(
+            plan(None),
+            [
+                Action::Mirror {
+                    url: SUPER.into(),
+                    path: mirror(SUPER),
+                },
+                Action::Clone {
+                    url: SUPER.into(),
+                    mirror: Some(mirror(SUPER)),
+                    target: target.clone(),
+                },
+                Action::UnknownSubmodules { url: SUPER.into() },
+            ]
+        );
+        assert!(f.backend.calls().is_empty());
+
+        f.cache
+            .prefetcher()
+            .repository_urls(vec![SUPER.to_string()])
+            .do_prefetch()
+            .unwrap();
+        let calls = f.backend.calls();
+
+        assert_eq!(
+            plan(Some("c2")),
+            [
+                Action::Update {
+                    path: mirror(SUPER),
+                    reason: "c2 is missing".into(),
+                },
+                Action::Clone {
+                    url: SUPER.into(),
+                    mirror: Some(mirror(SUPER)),
+                    target: target.clone(),
+                },
+                Action::Checkout {
+                    target: target.clone(),
+                    commit: "c2".into(),
+                },
+                Action::UnknownSubmodules { url: SUPER.into() },
+            ]
+        );
+        assert_eq!(
+            plan(Some("c1")),
+            [
+                Action::UseCached {
+                    path: mirror(SUPER),
+                },
+                Action::Clone {
+                    url: SUPER.into(),
+                    mirror: Some(mirror(SUPER)),
+                    target: target.clone(),
+                },
+                Action::Checkout {
+                    target: target.clone(),
+                    commit: "c1".into(),
+                },
+                Action::Mirror {
+                    url: SUB.into(),
+                    path: mirror(SUB),
+                },
+                Action::Clone {
+                    url: SUB.into(),
+                    mirror: Some(mirror(SUB)),
+                    target: target.join("sub"),
+                },
+                Action::Checkout {
+                    target: target.join("sub"),
+                    commit: "s1".into(),
+                },
+                Action::UnknownSubmodules { url: SUB.into() },
+                Action::SkipSubmodule {
+                    path: "docs".into(),
+                    reason: "filtered".into(),
+                },
+            ]
+        );
+
+        let plan = f
+            .cache
+            .prefetcher()
+            .repository_urls(vec![SUPER.to_string()])
+            .recurse_all_submodules(true)
+            .plan()
+            .unwrap();
+        assert_eq!(
+            plan,
+            [
+                Action::UseCached {
+                    path: mirror(SUPER),
+                },
+                Action::Mirror {
+                    url: NESTED.into(),
+                    path: mirror(NESTED),
+                },
+                Action::UnknownSubmodules { url: NESTED.into() },
+                Action::Mirror {
+                    url: SUB.into(),
+                    path: mirror(SUB),
+                },
+                Action::UnknownSubmodules { url: SUB.into() },
+            ]
+        );
+
+        assert_eq!(f.backend.calls(), calls);
+        assert!(!target.exists());
+    }
+
+    #[test]
+    fn prefetch_deduplicates_shared_submodules() {
+        let f = fixture();
+        let other = "https://example.com/other.git";
+        f.backend.set_upstream(
+            SUPER,
+            FakeRepo::new(&["c1"])
+                .with_submodule("sub", SUB, "s1")
+                .with_submodule("nested", NESTED, "n1"),
+        );
+        // the same mirror, by another URL
+        f.backend.set_upstream(
+            SUB,
+            FakeRepo::new(&["s1"]).with_submodule("nested", "https://example.com/nested", "n1"),
+        );
+        f.backend.set_upstream(
+            other,
+            FakeRepo::new(&["o1"]).with_submodule("sub", SUB, "s1"),
+        );
+        f.backend.set_upstream(NESTED, FakeRepo::new(&["n1"]));
+
+        let summary = f
+            .cache
+            .prefetcher()
+            .repository_urls(vec![
+                SUPER.to_string(),
+                other.to_string(),
+                SUPER.to_string(),
+            ])
+            .recurse_all_submodules(true)
+            .jobs(Some(2))
+            .do_prefetch()
+            .unwrap();
+
+        assert_eq!(summary.succeeded.len(), 4);
+        for url in [SUPER, SUB, NESTED, other] {
+            assert_eq!(f.backend.count(&format!("mirror {url}")), 1);
+        }
+        assert_eq!(summary.graph.roots, [SUPER, other]);
+        assert_eq!(
+            summary.graph.submodules[SUPER],
+            [SUB.into(), NESTED.into()].into()
+        );
+        assert_eq!(summary.graph.submodules[SUB], [NESTED.into()].into());
+        assert_eq!(summary.graph.submodules[other], [SUB.into()].into());
+    }
+
+    #[test]
+    fn prefetch_fetches_unadvertised_commits() {
+        let f = fixture();
+        let hidden = "0123456789abcdef0123456789abcdef01234567";
+        let missing = "89abcdef0123456789abcdef0123456789abcdef";
+        let mut upstream = FakeRepo::new(&["c1"]);
+        upstream.hidden_commits.insert(hidden.to_string());
+        f.backend.set_upstream(SUPER, upstream);
+
+        let entry = |commit: &str| PrefetchEntry::parse(&format!("{SUPER}@{commit}"));
+        f.cache
+            .prefetcher()
+            .entries(vec![entry("c1")])
+            .do_prefetch()
+            .unwrap();
+        f.cache
+            .prefetcher()
+            .entries(vec![entry("c1"), entry(hidden)])
+            .do_prefetch()
+            .unwrap();
+        // updating doesn't bring in the commit, fetching it by id does
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 1);
+        assert_eq!(f.backend.count(&format!("fetch {SUPER} {hidden}")), 1);
+
+        let summary = f
+            .cache
+            .prefetcher()
+            .entries(vec![entry("c1"), entry(missing)])
+            .do_prefetch()
+            .unwrap();
+        assert_eq!(summary.succeeded, [entry("c1")]);
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.failed[0].0, entry(missing));
+    }
+
+    #[test]
+    fn prefetch_merges_commits_per_mirror() {
+        use crate::plan::Action;
+
+        let f = fixture();
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1"]));
+        f.cache
+            .prefetcher()
+            .repository_urls(vec![SUPER.to_string()])
+            .do_prefetch()
+            .unwrap();
+
+        f.backend
+            .set_upstream(SUPER, FakeRepo::new(&["c1", "c2", "c3"]));
+        let entries = ["c2", "c3", "c2"]
+            .map(|commit| PrefetchEntry::parse(&format!("{SUPER}@{commit}")))
+            .to_vec();
+
+        let plan = f
+            .cache
+            .prefetcher()
+            .entries(entries.clone())
+            .plan()
+            .unwrap();
+        assert_eq!(
+            plan,
+            [Action::Update {
+                path: f.cache.repo(SUPER).repo().path().to_path_buf(),
+                reason: "c2, c3 are missing".into(),
+            }]
+        );
+
+        let summary = f
+            .cache
+            .prefetcher()
+            .entries(entries.clone())
+            .jobs(Some(2))
+            .do_prefetch()
+            .unwrap();
+        assert_eq!(summary.succeeded, entries[..2]);
+        assert_eq!(f.backend.count(&format!("fetch {SUPER}")), 1);
+    }
+
+    #[test]
+    fn prefetch_stops_after_failure_unless_keep_going() {
+        let f = fixture();
+        f.backend.set_upstream(SUPER, FakeRepo::new(&["c1"]));
+        let urls = vec![SUB.to_string(), SUPER.to_string()];
+
+        let summary = f
+            .cache
+            .prefetcher()
+            .repository_urls(urls.clone())
+            .do_prefetch()
+            .unwrap();
+        assert!(!summary.is_success());
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.skipped, [PrefetchEntry::new(SUPER.to_string())]);
+        assert_eq!(f.backend.count(&format!("mirror {SUPER}")), 0);
+
+        let summary = f
+            .cache
+            .prefetcher()
+            .repository_urls(urls)
+            .keep_going(true)
+            .do_prefetch()
+            .unwrap();
+        assert_eq!(summary.failed.len(), 1);
+        assert_eq!(summary.succeeded, [PrefetchEntry::new(SUPER.to_string())]);
+        assert!(summary.skipped.is_empty());
+    }
 }
@@ -1,9 +1,17 @@
 use std::process::ExitCode;
+use std::time::Duration;
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use camino::Utf8PathBuf;
 use clap::crate_version;
 use git_cache::GitCache;
+use git_cache::cargo;
+use git_cache::config::UrlConfig;
+use git_cache::doctor::Severity;
+use git_cache::manifest::Manifest;
+use git_cache::plan::Action;
+use git_cache::prefetch::{self, PrefetchEntry};
+use git_cache::retry::RetryPolicy;
 
 fn clap() -> clap::Command {
     use clap::Command;
@@ -13,8 +21,17 @@ fn clap() -> clap::Command {
         .about("A git repository cache tool")
         .infer_subcommands(true)
         .arg(git_cache::clap_git_cache_dir_arg())
+        .arg(git_cache::clap_git_cache_shared_arg())
+        .arg(git_cache::clap_git_cache_local_repos_arg())
+        .args(git_cache::clap_git_cache_retry_args())
         .subcommand(git_cache::clap_clone_command("clone"))
         .subcommand(git_cache::clap_prefetch_command("prefetch"))
+        .subcommand(git_cache::clap_fetch_command("fetch"))
+        .subcommand(git_cache::clap_sync_command("sync"))
+        .subcommand(git_cache::clap_export_command("export"))
+        .subcommand(git_cache::clap_import_command("import"))
+        .subcommand(git_cache::clap_doctor_command("doctor"))
+        .subcommand(git_cache::clap_path_command("path"))
         .subcommand(
             // this is a noop, we keep it for backwards compatibility with the
             // previous shell implementation
@@ -25,9 +42,27 @@ fn clap() -> clap::Command {
 fn main() -> Result<ExitCode> {
     let matches = clap().get_matches();
 
-    let cache_dir = Utf8PathBuf::from(&shellexpand::tilde(
-        matches.get_one::<Utf8PathBuf>("git_cache_dir").unwrap(),
-    ));
+    let cache_dirs = matches
+        .get_many::<Utf8PathBuf>("git_cache_dir")
+        .unwrap()
+        .cloned()
+        .collect::<Vec<_>>();
+    let shared_cache = matches.get_flag("shared_cache");
+    let cache_local_repos = matches.get_flag("cache_local_repos");
+    let retry = RetryPolicy {
+        retries: *matches.get_one::<u32>("retries").unwrap(),
+        backoff: Duration::from_secs(*matches.get_one::<u64>("retry_backoff").unwrap()),
+        timeout: matches
+            .get_one::<u64>("timeout")
+            .map(|timeout| Duration::from_secs(*timeout)),
+    };
+    let open_cache = || -> Result<GitCache> {
+        Ok(GitCache::from_dirs(cache_dirs.clone())?
+            .with_shared(shared_cache)
+            .with_cache_local_repos(cache_local_repos)
+            .with_url_config(UrlConfig::from_globals()?)
+            .with_retry_policy(retry.clone()))
+    };
 
     match matches.subcommand() {
         Some(("clone", matches)) => {
@@ -48,6 +83,12 @@ fn main() -> Result<ExitCode> {
                 && matches.contains_id("recurse-submodules");
 
             let shallow_submodules = matches.get_flag("shallow-submodules");
+            let remote_submodules = matches.get_flag("remote-submodules");
+            let exclude_submodules = matches
+                .get_many::<String>("exclude-submodule")
+                .map(|v| v.into_iter().cloned().collect::<Vec<String>>())
+                .unwrap_or_default();
+            let submodule_depth = matches.get_one::<usize>("submodule-depth").copied();
             if shallow_submodules {
                 println!("git-cache: warning: shallow submodule clones not supported");
             }
@@ -63,9 +104,11 @@ fn main() -> Result<ExitCode> {
                     .map(|v| v.value as usize);
             }
 
-            let git_cache = GitCache::new(cache_dir)?;
-            git_cache
-                .cloner()
+            let mirror_filter = matches.get_one::<String>("filter").cloned();
+
+            let git_cache = open_cache()?.with_mirror_filter(mirror_filter);
+            let mut cloner = git_cache.cloner();
+            cloner
                 .commit(wanted_commit.cloned())
                 .extra_clone_args_from_matches(matches)
                 .repository_url(repository.clone())
@@ -75,16 +118,50 @@ fn main() -> Result<ExitCode> {
                 .recurse_submodules(recurse_submodules)
                 .recurse_all_submodules(recurse_all_submodules)
                 .shallow_submodules(shallow_submodules)
-                .jobs(jobs)
-                .do_clone()?;
+                .remote_submodules(remote_submodules)
+                .exclude_submodules(exclude_submodules)
+                .submodule_depth(submodule_depth)
+                .also_filter_submodules(matches.get_flag("also-filter-submodules"))
+                .jobs(jobs);
+
+            if matches.get_flag("dry-run") {
+                print_plan(&cloner.plan()?);
+            } else {
+                cloner.do_clone()?;
+            }
         }
         Some(("prefetch", matches)) => {
-            let repositories = matches
+            let mut entries = matches
                 .get_many::<String>("repositories")
-                .map(|v| v.into_iter().cloned().collect::<Vec<String>>())
-                .unwrap_or_default();
+                .into_iter()
+                .flatten()
+                .map(|spec| PrefetchEntry::parse(spec))
+                .collect::<Vec<_>>();
+            if let Some(path) = matches.get_one::<String>("from-file") {
+                entries.extend(prefetch::read_list(path)?);
+            }
+            let mut cargo_sources = Vec::new();
+            for path in matches
+                .get_many::<Utf8PathBuf>("cargo-lock")
+                .into_iter()
+                .flatten()
+            {
+                cargo_sources.extend(cargo::read_lock(path)?);
+            }
+            entries.extend(cargo_sources.iter().map(|source| source.prefetch_entry()));
+
+            let mut submodule_refs = matches
+                .get_many::<String>("submodule-refs")
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            if matches.get_flag("all-refs") {
+                submodule_refs.extend(["refs/heads".to_string(), "refs/tags".to_string()]);
+            }
 
-            let recurse_submodules = matches.get_flag("recurse-submodules");
+            let recurse_submodules =
+                matches.get_flag("recurse-submodules") || !submodule_refs.is_empty();
             let update = matches.get_flag("update");
 
             let mut jobs = matches.get_one::<usize>("jobs").copied();
@@ -98,14 +175,160 @@ fn main() -> Result<ExitCode> {
                     .map(|v| v.value as usize);
             }
 
-            let git_cache = GitCache::new(cache_dir)?;
-            git_cache
-                .prefetcher()
+            // stdout is taken by git-cache's own output
+            let cargo_config = matches.get_one::<Utf8PathBuf>("cargo-config");
+            if cargo_config.is_some_and(|path| path == "-") {
+                bail!("--cargo-config needs a file, writing to stdout is not supported");
+            }
+            if let Some(path) = cargo_config {
+                cargo::check_config_file(path)?;
+            }
+
+            let mirror_filter = matches.get_one::<String>("filter").cloned();
+
+            let git_cache = open_cache()?.with_mirror_filter(mirror_filter);
+            let mut prefetcher = git_cache.prefetcher();
+            prefetcher
                 .jobs(jobs)
-                .repository_urls(repositories)
+                .entries(entries)
                 .update(update)
                 .recurse_all_submodules(recurse_submodules)
-                .do_prefetch()?;
+                .keep_going(matches.get_flag("keep-going"))
+                .submodule_refs(submodule_refs);
+
+            if matches.get_flag("dry-run") {
+                print_plan(&prefetcher.plan()?);
+                return Ok(ExitCode::SUCCESS);
+            }
+            let summary = prefetcher.do_prefetch()?;
+
+            if matches.get_flag("show-graph") {
+                print!("{}", summary.graph);
+            }
+
+            if let Some(path) = cargo_config {
+                std::fs::write(path, cargo::config_snippet(&git_cache, &cargo_sources)?)?;
+            }
+
+            if !summary.is_success() {
+                println!(
+                    "git-cache: {} of {} repositories could not be prefetched:",
+                    summary.failed.len() + summary.skipped.len(),
+                    summary.total()
+                );
+                for (entry, e) in &summary.failed {
+                    println!("git-cache:   {entry}: {e:#}");
+                }
+                for entry in &summary.skipped {
+                    println!("git-cache:   {entry}: skipped");
+                }
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(("fetch", matches)) => {
+            let path = matches.get_one::<Utf8PathBuf>("path").unwrap();
+            let remote = matches.get_one::<String>("remote").unwrap();
+
+            let git_cache = open_cache()?;
+            git_cache
+                .fetcher()
+                .path(path.clone())
+                .remote(remote.clone())
+                .prune(matches.get_flag("prune"))
+                .do_fetch()?;
+        }
+        Some(("sync", matches)) => {
+            let workspace = matches.get_one::<Utf8PathBuf>("workspace").unwrap();
+            let manifest = if let Some(west) = matches.get_one::<Utf8PathBuf>("west") {
+                Manifest::from_west_path(west)?
+            } else if let Some(repo) = matches.get_one::<Utf8PathBuf>("repo") {
+                let manifest_url = matches.get_one::<String>("manifest-url");
+                Manifest::from_repo_path(repo, manifest_url.map(String::as_str))?
+            } else {
+                Manifest::from_path(matches.get_one::<Utf8PathBuf>("manifest").unwrap())?
+            };
+
+            let git_cache = open_cache()?;
+            git_cache
+                .syncer()
+                .entries(manifest.repositories)
+                .workspace(workspace.clone())
+                .update(matches.get_flag("update"))
+                .jobs(matches.get_one::<usize>("jobs").copied())
+                .do_sync()?;
+        }
+        Some(("export", matches)) => {
+            let repositories = matches
+                .get_many::<String>("repositories")
+                .map(|v| v.into_iter().cloned().collect::<Vec<String>>())
+                .unwrap_or_default();
+            let bundle_dir = matches.get_one::<Utf8PathBuf>("bundle-dir").unwrap();
+
+            let git_cache = open_cache()?;
+            let uncached = git_cache.export_bundles(&repositories, bundle_dir)?;
+            if !uncached.is_empty() {
+                println!(
+                    "git-cache: {} of {} repositories were not exported, as they are not cached:",
+                    uncached.len(),
+                    repositories.len()
+                );
+                for url in &uncached {
+                    println!("git-cache:   {url}");
+                }
+                return Ok(ExitCode::FAILURE);
+            }
+        }
+        Some(("import", matches)) => {
+            let bundle_dir = matches.get_one::<Utf8PathBuf>("bundle-dir").unwrap();
+
+            let git_cache = open_cache()?;
+            git_cache.import_bundles(bundle_dir)?;
+        }
+        Some(("doctor", _matches)) => {
+            let git_cache = open_cache()?;
+            let findings = git_cache.doctor()?;
+            for finding in &findings {
+                println!("git-cache: {finding}");
+            }
+
+            let problems = findings
+                .iter()
+                .filter(|finding| finding.severity == Severity::Problem)
+                .count();
+            if problems > 0 {
+                println!("git-cache: found {problems} problem(s)");
+                return Ok(ExitCode::FAILURE);
+            }
+            println!("git-cache: no problems found");
+        }
+        Some(("path", matches)) => {
+            let git_cache = open_cache()?;
+
+            let mut missing = false;
+            for url in matches.get_many::<String>("repositories").unwrap() {
+                if !git_cache.is_cacheable(url) {
+                    bail!("can only cache remote repositories, '{url}' is local");
+                }
+                // the first tier that has the mirror, or the writable one
+                let cache_repo = git_cache.repo(url);
+                let cached_path = cache_repo.cached_path()?;
+                let path = cached_path.unwrap_or(cache_repo.path());
+                missing |= cached_path.is_none();
+
+                if matches.get_flag("status") {
+                    let status = match cached_path {
+                        Some(_) => "cached",
+                        None => "not cached",
+                    };
+                    println!("{path}\t{status}");
+                } else {
+                    println!("{path}");
+                }
+            }
+
+            if missing && matches.get_flag("check") {
+                return Ok(ExitCode::FAILURE);
+            }
         }
         Some(("other", _matches)) => {}
         _ => {}
@@ -113,3 +336,9 @@ fn main() -> Result<ExitCode> {
 
     Ok(0.into())
 }
+
+fn print_plan(actions: &[Action]) {
+    for action in actions {
+        println!("git-cache: would {action}");
+    }
+}
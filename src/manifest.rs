@@ -0,0 +1,286 @@
+//! Manifests describing a set of repositories to clone (`git cache sync`).
+//!
+//! The native format is TOML:
+//!
+//! ```toml
+//! [[repository]]
+//! url = "https://github.com/RIOT-OS/RIOT"
+//! path = "RIOT"                 # defaults to the last URL component
+//! commit = "3f1a0e3..."         # or `branch = "master"`
+//! sparse = ["core", "cpu/nrf52"]
+//! submodules = true             # or a list of submodule paths
+//! update = false                # update the cached mirror first
+//! ```
+//!
+//! Manifests ending in `.yml` or `.yaml` are read as YAML, with the same
+//! structure:
+//!
+//! ```yaml
+//! repository:
+//!   - url: https://github.com/RIOT-OS/RIOT
+//!     branch: master
+//!     submodules: [pkg/lwip]
+//! ```
+//!
+//! Zephyr `west.yml` and Android `repo` manifests can be translated into the
+//! same entries, see [`west`] and [`repo`].
+
+pub mod repo;
+pub mod west;
+
+use anyhow::{anyhow, Context as _, Error, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default, rename = "repository")]
+    pub repositories: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub url: String,
+    /// checkout path, relative to the workspace
+    pub path: Option<Utf8PathBuf>,
+    pub commit: Option<String>,
+    pub branch: Option<String>,
+    pub sparse: Option<Vec<String>>,
+    #[serde(default)]
+    pub submodules: SubmodulePolicy,
+    #[serde(default)]
+    pub update: bool,
+}
+
+/// Which submodules to clone for a manifest entry.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(from = "SubmodulePolicyValue")]
+pub enum SubmodulePolicy {
+    #[default]
+    None,
+    All,
+    Paths(Vec<String>),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SubmodulePolicyValue {
+    Bool(bool),
+    Paths(Vec<String>),
+}
+
+impl From<SubmodulePolicyValue> for SubmodulePolicy {
+    fn from(value: SubmodulePolicyValue) -> Self {
+        match value {
+            SubmodulePolicyValue::Bool(false) => SubmodulePolicy::None,
+            SubmodulePolicyValue::Bool(true) => SubmodulePolicy::All,
+            SubmodulePolicyValue::Paths(paths) => SubmodulePolicy::Paths(paths),
+        }
+    }
+}
+
+impl Manifest {
+    /// Reads a manifest, as YAML if `path` ends in `.yml` or `.yaml`, as TOML
+    /// otherwise.
+    pub fn from_path(path: &Utf8Path) -> Result<Self, Error> {
+        let data =
+            std::fs::read_to_string(path).with_context(|| format!("reading manifest \"{path}\""))?;
+        match path.extension() {
+            Some("yml" | "yaml") => Self::from_yaml(&data),
+            _ => Self::from_toml(&data),
+        }
+        .with_context(|| format!("parsing manifest \"{path}\""))
+    }
+
+    pub fn from_toml(data: &str) -> Result<Self, Error> {
+        toml::from_str::<Manifest>(data)?.validate()
+    }
+
+    pub fn from_yaml(data: &str) -> Result<Self, Error> {
+        serde_norway::from_str::<Manifest>(data)?.validate()
+    }
+
+    fn validate(self) -> Result<Self, Error> {
+        for entry in &self.repositories {
+            if entry.commit.is_some() && entry.branch.is_some() {
+                return Err(anyhow!(
+                    "{}: only one of `commit` and `branch` may be given",
+                    entry.url
+                ));
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl ManifestEntry {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            path: None,
+            commit: None,
+            branch: None,
+            sparse: None,
+            submodules: SubmodulePolicy::None,
+            update: false,
+        }
+    }
+
+    /// Sets `commit` or `branch` from a west/repo style revision.
+    ///
+    /// Commit ids and tags (`refs/tags/...`) get checked out detached,
+    /// everything else is treated as a branch name.
+    pub fn set_revision(&mut self, revision: &str) {
+        if crate::is_commit_id(revision) {
+            self.commit = Some(revision.to_string());
+        } else if let Some(tag) = revision.strip_prefix("refs/tags/") {
+            self.commit = Some(tag.to_string());
+        } else {
+            let branch = revision.strip_prefix("refs/heads/").unwrap_or(revision);
+            self.branch = Some(branch.to_string());
+        }
+    }
+
+    /// Returns the checkout path, defaulting to the URL's last path component
+    /// without `.git`, like `git clone` does.
+    pub fn target_path(&self) -> Utf8PathBuf {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
+
+        let name = self.url.trim_end_matches('/');
+        let name = name.rsplit(['/', ':']).next().unwrap_or(name);
+        Utf8PathBuf::from(name.strip_suffix(".git").unwrap_or(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+[[repository]]
+url = "https://github.com/RIOT-OS/RIOT"
+branch = "master"
+sparse = ["core"]
+submodules = ["pkg/lwip"]
+
+[[repository]]
+url = "git@github.com:foo/bar.git"
+path = "libs/bar"
+commit = "3f1a0e3"
+submodules = true
+update = true
+"#;
+
+    const YAML: &str = r#"
+repository:
+  - url: https://github.com/RIOT-OS/RIOT
+    branch: master
+    sparse: [core]
+    submodules: [pkg/lwip]
+  - url: git@github.com:foo/bar.git
+    path: libs/bar
+    commit: 3f1a0e3
+    submodules: true
+    update: true
+"#;
+
+    fn check(manifest: Manifest) {
+        let [riot, bar] = &manifest.repositories[..] else {
+            panic!("{manifest:?}");
+        };
+
+        assert_eq!(riot.url, "https://github.com/RIOT-OS/RIOT");
+        assert_eq!(riot.target_path(), "RIOT");
+        assert_eq!(riot.branch.as_deref(), Some("master"));
+        assert_eq!(riot.commit, None);
+        assert_eq!(riot.sparse, Some(vec!["core".to_string()]));
+        assert_eq!(
+            riot.submodules,
+            SubmodulePolicy::Paths(vec!["pkg/lwip".into()])
+        );
+        assert!(!riot.update);
+
+        assert_eq!(bar.target_path(), "libs/bar");
+        assert_eq!(bar.commit.as_deref(), Some("3f1a0e3"));
+        assert_eq!(bar.submodules, SubmodulePolicy::All);
+        assert!(bar.update);
+    }
+
+    #[test]
+    fn toml() {
+        check(Manifest::from_toml(TOML).unwrap());
+    }
+
+    #[test]
+    fn yaml() {
+        check(Manifest::from_yaml(YAML).unwrap());
+    }
+
+    #[test]
+    fn from_path_by_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        for (name, data) in [
+            ("manifest.toml", TOML),
+            ("manifest.yaml", YAML),
+            ("m.yml", YAML),
+        ] {
+            std::fs::write(dir.join(name), data).unwrap();
+            check(Manifest::from_path(&dir.join(name)).unwrap());
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for toml in [
+            "[[repository]]\nurl = \"a\"\ncommit = \"abc1234\"\nbranch = \"main\"\n",
+            "[[repository]]\nurl = \"a\"\nrevision = \"main\"\n",
+            "[[repository]]\npath = \"a\"\n",
+            "[[repository]]\nurl = \"a\"\nsubmodules = \"yes\"\n",
+        ] {
+            assert!(Manifest::from_toml(toml).is_err(), "{toml}");
+        }
+    }
+
+    #[test]
+    fn revisions() {
+        for (revision, commit, branch) in [
+            ("3f1a0e3", Some("3f1a0e3"), None),
+            (
+                "4b96cbb174678dcd3ca86e11e1f24bc5f8726da0",
+                Some("4b96cbb174678dcd3ca86e11e1f24bc5f8726da0"),
+                None,
+            ),
+            ("refs/tags/v3.7.0", Some("v3.7.0"), None),
+            ("v3.7.0", None, Some("v3.7.0")),
+            ("main", None, Some("main")),
+            ("refs/heads/main", None, Some("main")),
+            // too short for a commit id
+            ("cafe", None, Some("cafe")),
+        ] {
+            let mut entry = ManifestEntry::new("https://example.com/foo".into());
+            entry.set_revision(revision);
+            assert_eq!(
+                (entry.commit.as_deref(), entry.branch.as_deref()),
+                (commit, branch),
+                "{revision}"
+            );
+        }
+    }
+
+    #[test]
+    fn target_paths() {
+        for (url, path) in [
+            ("https://github.com/RIOT-OS/RIOT", "RIOT"),
+            ("https://github.com/RIOT-OS/RIOT.git/", "RIOT"),
+            ("git@github.com:foo.git", "foo"),
+            ("/srv/git/bar.git", "bar"),
+        ] {
+            assert_eq!(ManifestEntry::new(url.into()).target_path(), path);
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! Android `repo` manifests (`default.xml`).
+//!
+//! Supported are `<remote>`, `<default>`, `<project>`, `<remove-project>` and
+//! `<include>`. Like with `repo`, includes are relative to the manifest
+//! repository, which is `.repo/manifests` for `.repo/manifest.xml` and the
+//! directory of the given manifest otherwise. Projects in the `notdefault`
+//! group are skipped, like `repo init` does without `-g`.
+//!
+//! Other elements that change what gets synced (e.g., `<extend-project>`,
+//! `<superproject>` or `<linkfile>`) are rejected, while `<notice>`,
+//! `<contactinfo>`, `<repo-hooks>` and `<annotation>` are ignored.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context as _, Error, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use super::{Manifest, ManifestEntry};
+
+#[derive(Default)]
+struct RepoManifest {
+    remotes: HashMap<String, RepoRemote>,
+    default_remote: Option<String>,
+    default_revision: Option<String>,
+    projects: Vec<RepoProject>,
+    /// the directory includes are relative to
+    root: Utf8PathBuf,
+    /// canonical paths of the manifests being read, to detect include cycles
+    including: Vec<Utf8PathBuf>,
+}
+
+struct RepoRemote {
+    fetch: String,
+    revision: Option<String>,
+}
+
+struct RepoProject {
+    name: String,
+    path: Option<String>,
+    remote: Option<String>,
+    revision: Option<String>,
+    groups: Vec<String>,
+}
+
+impl Manifest {
+    /// Reads a `repo` manifest.
+    ///
+    /// Remotes usually specify their fetch URL relative to the URL the
+    /// manifest repository was cloned from (e.g., `fetch=".."`). Those can
+    /// only be resolved if `manifest_url` is given.
+    pub fn from_repo_path(path: &Utf8Path, manifest_url: Option<&str>) -> Result<Self, Error> {
+        let mut repo_manifest = RepoManifest {
+            root: manifest_root(path),
+            ..Default::default()
+        };
+        repo_manifest
+            .read(path)
+            .with_context(|| format!("parsing repo manifest \"{path}\""))?;
+        repo_manifest.into_manifest(manifest_url)
+    }
+}
+
+impl RepoManifest {
+    fn read(&mut self, path: &Utf8Path) -> Result<()> {
+        let canonical = path
+            .canonicalize_utf8()
+            .with_context(|| format!("reading manifest \"{path}\""))?;
+        if self.including.contains(&canonical) {
+            bail!("\"{path}\" includes itself (include cycle)");
+        }
+        self.including.push(canonical);
+
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading manifest \"{path}\""))?;
+        let doc = roxmltree::Document::parse(&data)?;
+
+        let root = doc.root_element();
+        if root.tag_name().name() != "manifest" {
+            bail!("root element is not <manifest>");
+        }
+
+        for node in root.children().filter(|node| node.is_element()) {
+            let attr = |name| node.attribute(name).map(|value: &str| value.to_string());
+            let required = |name| {
+                attr(name)
+                    .ok_or_else(|| anyhow!("<{}> is missing `{name}`", node.tag_name().name()))
+            };
+
+            match node.tag_name().name() {
+                "remote" => {
+                    self.remotes.insert(
+                        required("name")?,
+                        RepoRemote {
+                            fetch: required("fetch")?,
+                            revision: attr("revision"),
+                        },
+                    );
+                }
+                "default" => {
+                    if let Some(remote) = attr("remote") {
+                        self.default_remote = Some(remote);
+                    }
+                    if let Some(revision) = attr("revision") {
+                        self.default_revision = Some(revision);
+                    }
+                }
+                "project" => {
+                    let name = required("name")?;
+                    if let Some(child) = node
+                        .children()
+                        .find(|child| child.is_element() && child.tag_name().name() != "annotation")
+                    {
+                        bail!(
+                            "project `{name}`: <{}> is not supported",
+                            child.tag_name().name()
+                        );
+                    }
+                    self.projects.push(RepoProject {
+                        name,
+                        path: attr("path"),
+                        remote: attr("remote"),
+                        revision: attr("revision"),
+                        groups: attr("groups")
+                            .map(|groups| {
+                                groups
+                                    .split([',', ' '])
+                                    .filter(|group| !group.is_empty())
+                                    .map(str::to_string)
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                    })
+                }
+                "remove-project" => {
+                    let name = required("name")?;
+                    self.projects.retain(|project| project.name != name);
+                }
+                "include" => {
+                    let name = required("name")?;
+                    self.read(&self.root.join(name))?;
+                }
+                // no effect on what gets synced
+                "notice" | "contactinfo" | "repo-hooks" => {}
+                other => bail!("<{other}> is not supported"),
+            }
+        }
+
+        self.including.pop();
+        Ok(())
+    }
+
+    fn into_manifest(self, manifest_url: Option<&str>) -> Result<Manifest> {
+        let mut repositories = Vec::new();
+
+        for project in self.projects {
+            if project.groups.iter().any(|group| group == "notdefault") {
+                continue;
+            }
+
+            let remote_name = project
+                .remote
+                .as_ref()
+                .or(self.default_remote.as_ref())
+                .ok_or_else(|| anyhow!("project `{}` has no remote", project.name))?;
+            let remote = self.remotes.get(remote_name).ok_or_else(|| {
+                anyhow!("project `{}`: unknown remote `{remote_name}`", project.name)
+            })?;
+
+            let fetch = resolve_fetch_url(&remote.fetch, manifest_url)?;
+            let url = format!("{}/{}", fetch.trim_end_matches('/'), project.name);
+
+            let revision = project
+                .revision
+                .as_ref()
+                .or(remote.revision.as_ref())
+                .or(self.default_revision.as_ref())
+                .ok_or_else(|| anyhow!("project `{}` has no revision", project.name))?;
+
+            let mut entry = ManifestEntry::new(url);
+            entry.path = Some(project.path.unwrap_or(project.name).into());
+            entry.set_revision(revision);
+
+            repositories.push(entry);
+        }
+
+        Ok(Manifest { repositories })
+    }
+}
+
+/// Returns the directory of the manifest repository for the manifest at
+/// `path`.
+fn manifest_root(path: &Utf8Path) -> Utf8PathBuf {
+    let dir = path.parent().unwrap_or(Utf8Path::new("."));
+    let manifests = dir.join("manifests");
+    if dir.file_name() == Some(".repo") && manifests.is_dir() {
+        manifests
+    } else {
+        dir.to_path_buf()
+    }
+}
+
+/// Resolves a remote's `fetch` attribute against the manifest URL, like
+/// `repo` does using `urljoin()`.
+fn resolve_fetch_url(fetch: &str, manifest_url: Option<&str>) -> Result<String> {
+    if url::Url::parse(fetch).is_ok() || crate::url_is_scp_scheme(fetch) {
+        return Ok(fetch.to_string());
+    }
+
+    let manifest_url = manifest_url.ok_or_else(|| {
+        anyhow!("remote fetch URL \"{fetch}\" is relative, the manifest URL is needed")
+    })?;
+    let base = url::Url::parse(manifest_url.trim_end_matches('/'))
+        .with_context(|| format!("parsing manifest URL \"{manifest_url}\""))?;
+
+    Ok(base.join(fetch)?.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Utf8Path, name: &str, data: &str) -> Utf8PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn projects() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        write(
+            dir,
+            "extra.xml",
+            r#"<manifest>
+  <remove-project name="platform/removed" />
+  <project name="device/extra" path="device/extra" remote="other" />
+</manifest>"#,
+        );
+        let path = write(
+            dir,
+            "default.xml",
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<manifest>
+  <remote name="aosp" fetch=".." />
+  <remote name="other" fetch="https://example.com/git/" revision="stable" />
+  <default remote="aosp" revision="refs/heads/main" />
+  <project name="platform/build" path="build/make" />
+  <project name="platform/removed" />
+  <project name="platform/pinned" revision="0123456789abcdef0123456789abcdef01234567" />
+  <project name="platform/tagged" revision="refs/tags/android-14.0.0_r1" />
+  <project name="platform/optional" groups="pdk,notdefault" />
+  <include name="extra.xml" />
+</manifest>"#,
+        );
+
+        let manifest = Manifest::from_repo_path(
+            &path,
+            Some("https://android.googlesource.com/platform/manifest"),
+        )
+        .unwrap();
+        let entries = manifest
+            .repositories
+            .iter()
+            .map(|entry| {
+                (
+                    entry.url.as_str(),
+                    entry.path.as_ref().unwrap().as_str(),
+                    entry.commit.as_deref(),
+                    entry.branch.as_deref(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [
+                (
+                    "https://android.googlesource.com/platform/build",
+                    "build/make",
+                    None,
+                    Some("main")
+                ),
+                (
+                    "https://android.googlesource.com/platform/pinned",
+                    "platform/pinned",
+                    Some("0123456789abcdef0123456789abcdef01234567"),
+                    None
+                ),
+                (
+                    "https://android.googlesource.com/platform/tagged",
+                    "platform/tagged",
+                    Some("android-14.0.0_r1"),
+                    None
+                ),
+                (
+                    "https://example.com/git/device/extra",
+                    "device/extra",
+                    None,
+                    Some("stable")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn relative_fetch_url_needs_manifest_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let path = write(
+            dir,
+            "default.xml",
+            r#"<manifest>
+  <remote name="aosp" fetch=".." />
+  <default remote="aosp" revision="main" />
+  <project name="platform/build" />
+</manifest>"#,
+        );
+
+        let e = Manifest::from_repo_path(&path, None).unwrap_err();
+        assert!(e.to_string().contains("is relative"), "{e}");
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let manifest = |include| {
+            format!(
+                r#"<manifest>
+  <remote name="origin" fetch="https://example.com/" />
+  <include name="{include}" />
+</manifest>"#
+            )
+        };
+
+        let path = write(dir, "self.xml", &manifest("self.xml"));
+        let e = Manifest::from_repo_path(&path, None).unwrap_err();
+        assert!(format!("{e:#}").contains("include cycle"), "{e:#}");
+
+        write(dir, "b.xml", &manifest("a.xml"));
+        let path = write(dir, "a.xml", &manifest("b.xml"));
+        let e = Manifest::from_repo_path(&path, None).unwrap_err();
+        assert!(format!("{e:#}").contains("include cycle"), "{e:#}");
+
+        // including a file twice is not a cycle
+        write(dir, "remote.xml", &manifest("empty.xml"));
+        write(dir, "empty.xml", "<manifest />");
+        let path = write(
+            dir,
+            "diamond.xml",
+            "<manifest>\n  <include name=\"remote.xml\" />\n  <include name=\"empty.xml\" />\n</manifest>",
+        );
+        Manifest::from_repo_path(&path, None).unwrap();
+    }
+
+    #[test]
+    fn includes_are_relative_to_manifest_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let manifests = dir.join(".repo/manifests");
+        std::fs::create_dir_all(manifests.join("sub")).unwrap();
+        write(
+            dir,
+            ".repo/manifest.xml",
+            r#"<manifest><include name="default.xml" /></manifest>"#,
+        );
+        write(
+            &manifests,
+            "default.xml",
+            r#"<manifest>
+  <remote name="origin" fetch="https://example.com/" revision="main" />
+  <default remote="origin" />
+  <include name="sub/a.xml" />
+</manifest>"#,
+        );
+        // relative to `.repo/manifests`, not to `sub/`
+        write(
+            &manifests,
+            "sub/a.xml",
+            r#"<manifest><include name="sub/b.xml" /></manifest>"#,
+        );
+        write(
+            &manifests,
+            "sub/b.xml",
+            r#"<manifest><project name="b" /></manifest>"#,
+        );
+
+        let manifest = Manifest::from_repo_path(&dir.join(".repo/manifest.xml"), None).unwrap();
+        assert_eq!(manifest.repositories.len(), 1);
+        assert_eq!(manifest.repositories[0].url, "https://example.com/b");
+    }
+
+    #[test]
+    fn unsupported_elements() {
+        let dir = tempfile::tempdir().unwrap();
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let manifest = |element: &str| {
+            format!(
+                r#"<manifest>
+  <notice>ignored</notice>
+  <remote name="origin" fetch="https://example.com/" revision="main" />
+  <default remote="origin" />
+  {element}
+</manifest>"#
+            )
+        };
+
+        let path = write(
+            dir,
+            "ok.xml",
+            &manifest(r#"<project name="a"><annotation name="x" value="y" /></project>"#),
+        );
+        Manifest::from_repo_path(&path, None).unwrap();
+
+        for (element, error) in [
+            (
+                r#"<extend-project name="a" revision="v1" />"#,
+                "<extend-project> is not supported",
+            ),
+            (
+                r#"<superproject name="super" />"#,
+                "<superproject> is not supported",
+            ),
+            (
+                r#"<project name="a"><linkfile src="a" dest="b" /></project>"#,
+                "project `a`: <linkfile> is not supported",
+            ),
+        ] {
+            let path = write(dir, "default.xml", &manifest(element));
+            let e = Manifest::from_repo_path(&path, None).unwrap_err();
+            assert!(format!("{e:#}").contains(error), "{e:#}");
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Zephyr `west.yml` manifests.
+//!
+//! Only the parts needed to clone the projects are supported: remotes,
+//! defaults, projects and group filters. The manifest repository itself
+//! (`self`) is expected to exist already.
+//!
+//! `import`s are not supported, manifests using them are rejected instead of
+//! silently syncing only a part of the workspace. Imported manifests (e.g.,
+//! `zephyr/west.yml`) can be synced on their own, once the project providing
+//! them exists.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Context as _, Error, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+
+use super::{Manifest, ManifestEntry, SubmodulePolicy};
+
+/// west's default when neither the project nor `defaults` set a revision
+const DEFAULT_REVISION: &str = "master";
+
+#[derive(Deserialize)]
+struct WestFile {
+    manifest: WestManifest,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct WestManifest {
+    #[serde(default)]
+    defaults: WestDefaults,
+    #[serde(default)]
+    remotes: Vec<WestRemote>,
+    #[serde(default)]
+    projects: Vec<WestProject>,
+    #[serde(default)]
+    group_filter: Vec<String>,
+}
+
+#[derive(Default, Deserialize)]
+struct WestDefaults {
+    remote: Option<String>,
+    revision: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct WestRemote {
+    name: String,
+    url_base: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct WestProject {
+    name: String,
+    url: Option<String>,
+    remote: Option<String>,
+    repo_path: Option<String>,
+    revision: Option<String>,
+    path: Option<Utf8PathBuf>,
+    #[serde(default)]
+    submodules: WestSubmodules,
+    #[serde(default)]
+    groups: Vec<String>,
+    import: Option<serde_norway::Value>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(untagged)]
+enum WestSubmodules {
+    #[default]
+    None,
+    Bool(bool),
+    List(Vec<WestSubmodule>),
+}
+
+#[derive(Deserialize)]
+struct WestSubmodule {
+    path: String,
+}
+
+impl Manifest {
+    pub fn from_west_path(path: &Utf8Path) -> Result<Self, Error> {
+        let data =
+            std::fs::read_to_string(path).with_context(|| format!("reading manifest \"{path}\""))?;
+        Self::from_west(&data).with_context(|| format!("parsing west manifest \"{path}\""))
+    }
+
+    pub fn from_west(data: &str) -> Result<Self, Error> {
+        let west: WestFile = serde_norway::from_str(data)?;
+        let west = west.manifest;
+
+        let remotes = west
+            .remotes
+            .iter()
+            .map(|remote| (remote.name.as_str(), remote.url_base.trim_end_matches('/')))
+            .collect::<HashMap<_, _>>();
+
+        let disabled_groups = west
+            .group_filter
+            .iter()
+            .filter_map(|group| group.strip_prefix('-'))
+            .collect::<Vec<_>>();
+
+        let mut repositories = Vec::new();
+        for project in west.projects {
+            // a project is inactive if all of its groups are disabled
+            if !project.groups.is_empty()
+                && project
+                    .groups
+                    .iter()
+                    .all(|group| disabled_groups.contains(&group.as_str()))
+            {
+                continue;
+            }
+
+            if !matches!(
+                project.import,
+                None | Some(serde_norway::Value::Bool(false))
+            ) {
+                bail!(
+                    "project `{}` imports other manifests, which is not supported",
+                    project.name
+                );
+            }
+
+            let url = match &project.url {
+                Some(url) => url.clone(),
+                None => {
+                    let remote = project
+                        .remote
+                        .as_ref()
+                        .or(west.defaults.remote.as_ref())
+                        .ok_or_else(|| anyhow!("project `{}` has no remote", project.name))?;
+                    let url_base = remotes.get(remote.as_str()).ok_or_else(|| {
+                        anyhow!("project `{}`: unknown remote `{remote}`", project.name)
+                    })?;
+                    let repo_path = project.repo_path.as_ref().unwrap_or(&project.name);
+                    format!("{url_base}/{repo_path}")
+                }
+            };
+
+            let mut entry = ManifestEntry::new(url);
+            entry.path = Some(
+                project
+                    .path
+                    .unwrap_or_else(|| Utf8PathBuf::from(&project.name)),
+            );
+            entry.set_revision(
+                project
+                    .revision
+                    .as_deref()
+                    .or(west.defaults.revision.as_deref())
+                    .unwrap_or(DEFAULT_REVISION),
+            );
+            entry.submodules = match project.submodules {
+                WestSubmodules::None | WestSubmodules::Bool(false) => SubmodulePolicy::None,
+                WestSubmodules::Bool(true) => SubmodulePolicy::All,
+                WestSubmodules::List(submodules) => SubmodulePolicy::Paths(
+                    submodules
+                        .into_iter()
+                        .map(|submodule| submodule.path)
+                        .collect(),
+                ),
+            };
+
+            repositories.push(entry);
+        }
+
+        Ok(Manifest { repositories })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn urls_and_revisions(manifest: &Manifest) -> Vec<(&str, &str, &str)> {
+        manifest
+            .repositories
+            .iter()
+            .map(|entry| {
+                (
+                    entry.url.as_str(),
+                    entry.path.as_ref().unwrap().as_str(),
+                    entry.commit.as_deref().or(entry.branch.as_deref()).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn projects() {
+        let manifest = Manifest::from_west(
+            r#"
+manifest:
+  defaults:
+    remote: upstream
+    revision: main
+  remotes:
+    - name: upstream
+      url-base: https://github.com/zephyrproject-rtos/
+    - name: babblesim
+      url-base: https://github.com/BabbleSim
+  group-filter: [-optional]
+  projects:
+    - name: cmsis
+      revision: 4b96cbb174678dcd3ca86e11e1f24bc5f8726da0
+      path: modules/hal/cmsis
+    - name: bsim
+      remote: babblesim
+      repo-path: base
+      revision: refs/tags/v2.0
+      submodules: true
+    - name: hal_nordic
+      revision: refs/heads/stable
+      submodules:
+        - path: nrfx
+    - name: custom
+      url: https://example.com/custom.git
+    - name: psa-arch-tests
+      groups: [optional]
+  self:
+    path: zephyr
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            urls_and_revisions(&manifest),
+            [
+                (
+                    "https://github.com/zephyrproject-rtos/cmsis",
+                    "modules/hal/cmsis",
+                    "4b96cbb174678dcd3ca86e11e1f24bc5f8726da0"
+                ),
+                ("https://github.com/BabbleSim/base", "bsim", "v2.0"),
+                (
+                    "https://github.com/zephyrproject-rtos/hal_nordic",
+                    "hal_nordic",
+                    "stable"
+                ),
+                ("https://example.com/custom.git", "custom", "main"),
+            ]
+        );
+        let submodules = manifest
+            .repositories
+            .iter()
+            .map(|entry| entry.submodules.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            submodules,
+            [
+                SubmodulePolicy::None,
+                SubmodulePolicy::All,
+                SubmodulePolicy::Paths(vec!["nrfx".into()]),
+                SubmodulePolicy::None,
+            ]
+        );
+        assert_eq!(manifest.repositories[1].commit.as_deref(), Some("v2.0"));
+        assert_eq!(manifest.repositories[2].branch.as_deref(), Some("stable"));
+    }
+
+    #[test]
+    fn default_revision() {
+        let manifest = Manifest::from_west(
+            "manifest:\n  projects:\n    - name: foo\n      url: https://example.com/foo\n",
+        )
+        .unwrap();
+        assert_eq!(
+            manifest.repositories[0].branch.as_deref(),
+            Some(DEFAULT_REVISION)
+        );
+    }
+
+    #[test]
+    fn errors() {
+        for (west, error) in [
+            ("manifest:\n  projects:\n    - name: foo\n", "has no remote"),
+            (
+                "manifest:\n  projects:\n    - name: foo\n      remote: nope\n",
+                "unknown remote `nope`",
+            ),
+            (
+                "manifest:\n  projects:\n    - name: zephyr\n      url: https://example.com/z\n      import: true\n",
+                "imports other manifests",
+            ),
+        ] {
+            let e = Manifest::from_west(west).unwrap_err();
+            assert!(e.to_string().contains(error), "{e}");
+        }
+
+        let west = "manifest:\n  projects:\n    - name: zephyr\n      url: https://example.com/z\n      import: false\n";
+        assert!(Manifest::from_west(west).is_ok());
+    }
+}
@@ -0,0 +1,85 @@
+//! Read-only repository queries using gitoxide.
+//!
+//! Spawning `git` for every query adds up for recursive clones of projects
+//! with many submodules. These functions answer the queries in-process.
+//! Callers fall back to the git CLI if any of them return an error.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use camino::Utf8Path;
+
+fn open(path: &Utf8Path) -> Result<gix::Repository> {
+    Ok(gix::open(path.as_std_path())?)
+}
+
+/// Returns `true` if `path` is the top level of a repository (bare or not).
+pub(crate) fn is_initialized(path: &Utf8Path) -> Result<bool> {
+    let repo = open(path)?;
+    let toplevel = repo.workdir().unwrap_or(repo.git_dir());
+    Ok(toplevel.canonicalize()? == path.canonicalize()?)
+}
+
+/// Returns `true` if `commit` resolves to a commit object.
+///
+/// Only object ids can be told apart from revisions that fail to resolve for
+/// other reasons, so other revisions that don't resolve are an error.
+pub(crate) fn has_commit(path: &Utf8Path, commit: &str) -> Result<bool> {
+    let repo = open(path)?;
+    let object = match gix::ObjectId::from_hex(commit.as_bytes()) {
+        Ok(id) => match repo.try_find_object(id)? {
+            Some(object) => object,
+            None => return Ok(false),
+        },
+        Err(_) => repo.rev_parse_single(commit)?.object()?,
+    };
+    Ok(object.peel_to_kind(gix::object::Kind::Commit).is_ok())
+}
+
+/// Returns the gitlinks (submodule commits) in the index, by path.
+pub(crate) fn submodule_commits(path: &Utf8Path) -> Result<HashMap<String, String>> {
+    let repo = open(path)?;
+    let index = repo.index_or_empty()?;
+
+    Ok(index
+        .entries()
+        .iter()
+        .filter(|entry| entry.mode == gix::index::entry::Mode::COMMIT)
+        .map(|entry| (entry.path(&index).to_string(), entry.id.to_string()))
+        .collect())
+}
+
+/// Returns the contents of the blob at `spec` (e.g., `HEAD:.gitmodules`), or
+/// `None` if the file does not exist.
+pub(crate) fn read_blob(path: &Utf8Path, spec: &str) -> Result<Option<Vec<u8>>> {
+    let (rev, file) = spec
+        .split_once(':')
+        .ok_or_else(|| anyhow!("\"{spec}\" is not `<rev>:<path>`"))?;
+    let repo = open(path)?;
+    let tree = repo.rev_parse_single(rev)?.object()?.peel_to_tree()?;
+    match tree.lookup_entry_by_path(file)? {
+        Some(entry) => Ok(Some(entry.object()?.detach().data)),
+        None => Ok(None),
+    }
+}
+
+/// Returns the gitlinks (submodule commits) at `paths` in the tree of `rev`,
+/// by path.
+pub(crate) fn submodule_commits_at(
+    path: &Utf8Path,
+    rev: &str,
+    paths: &[String],
+) -> Result<HashMap<String, String>> {
+    let repo = open(path)?;
+    let tree = repo.rev_parse_single(rev)?.object()?.peel_to_tree()?;
+
+    let mut commits = HashMap::new();
+    for path in paths {
+        if let Some(entry) = tree.lookup_entry_by_path(path)? {
+            if entry.mode().is_commit() {
+                commits.insert(path.clone(), entry.object_id().to_string());
+            }
+        }
+    }
+    Ok(commits)
+}
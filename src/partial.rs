@@ -0,0 +1,99 @@
+//! Partial clone mirrors (`git clone --mirror --filter=...`).
+//!
+//! Clones from a partial mirror are partial clones themselves, with the
+//! mirror configured as additional promisor remote ([`PROMISOR_REMOTE`]).
+//! Objects missing in the clone are then fetched through the mirror, which
+//! in turn fetches them from the upstream and keeps them for the next clone.
+//! `origin` still points upstream.
+
+use anyhow::{anyhow, Result};
+
+use crate::{GitRepo, TrueOr};
+
+/// name of the remote pointing at the mirror in clones of partial mirrors
+pub(crate) const PROMISOR_REMOTE: &str = "gitcache";
+
+/// Returns the filter a mirror was created with, if it is a partial clone.
+pub(crate) fn filter(mirror: &GitRepo) -> Result<Option<String>> {
+    if mirror.get_config("remote.origin.promisor")?.as_deref() != Some("true") {
+        return Ok(None);
+    }
+    mirror.get_config("remote.origin.partialclonefilter")
+}
+
+/// Lets clones of the partial mirror request single objects and filtered
+/// packs, as needed when fetching missing objects.
+pub(crate) fn serve_filtered(mirror: &GitRepo) -> Result<()> {
+    mirror.set_config("uploadpack.allowFilter", "true")?;
+    mirror.set_config("uploadpack.allowAnySHA1InWant", "true")
+}
+
+/// Returns the `upload-pack` command to run in the mirror, as shell command.
+///
+/// Since git 2.45.1 (and some backports), `upload-pack` doesn't fetch
+/// missing objects by itself, unless asked to using `GIT_NO_LAZY_FETCH`.
+/// This is fine for our own mirrors.
+pub(crate) fn upload_pack(mirror: &GitRepo) -> String {
+    if mirror.safe_directory {
+        format!(
+            "GIT_NO_LAZY_FETCH=0 git -c {} upload-pack",
+            shell_quote(&format!("safe.directory={}", mirror.path))
+        )
+    } else {
+        "GIT_NO_LAZY_FETCH=0 git upload-pack".to_string()
+    }
+}
+
+/// Quotes `s` for a POSIX shell, using single quotes.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Turns `target`, a `--no-checkout` clone of the partial `mirror`, into a
+/// partial clone using the mirror as promisor.
+pub(crate) fn use_mirror_as_promisor(
+    target: &GitRepo,
+    mirror: &GitRepo,
+    filter: &str,
+) -> Result<()> {
+    let remote = PROMISOR_REMOTE;
+    target.set_config("core.repositoryFormatVersion", "1")?;
+    target.set_config("extensions.partialClone", remote)?;
+    target.set_config(&format!("remote.{remote}.url"), mirror.path.as_str())?;
+    target.set_config(&format!("remote.{remote}.promisor"), "true")?;
+    target.set_config(&format!("remote.{remote}.partialCloneFilter"), filter)?;
+    target.set_config(&format!("remote.{remote}.uploadpack"), &upload_pack(mirror))?;
+    // `git fetch --all` should keep fetching from upstream only
+    target.set_config(&format!("remote.{remote}.skipFetchAll"), "true")
+}
+
+/// Checks out `HEAD` in a fresh `--no-checkout` clone.
+pub(crate) fn checkout_head(target: &GitRepo) -> Result<()> {
+    target
+        .git()
+        .arg("reset")
+        .arg("--quiet")
+        .arg("--hard")
+        .status()?
+        .success()
+        .true_or(anyhow!("error checking out HEAD"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn quoting() {
+        for s in ["plain", "with space", "it's", "'", "$HOME \\ `ls`", ""] {
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_quote(s)))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), s);
+        }
+    }
+}
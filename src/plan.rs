@@ -0,0 +1,392 @@
+//! What a clone or prefetch would do (`--dry-run`).
+//!
+//! Plans are made from what's in the cache: mirror paths are resolved like
+//! for the real thing, existing mirrors are checked for the wanted commits,
+//! and submodules are read from the mirrors. Nothing gets created, fetched or
+//! locked, so submodules of repositories that aren't cached yet are unknown.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+
+use anyhow::{Error, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::prefetch::PrefetchEntry;
+use crate::submodule::{self, SubmoduleFilter};
+use crate::{
+    absolute_local_url, is_commit_id, resolve_submodule_url, submodule_entries,
+    target_path_from_url_maybe, GitCache, GitCacheCloner, GitCacheClonerBuilder,
+    GitCachePrefetcher, GitCachePrefetcherBuilder, GitCacheRepo,
+};
+
+/// A step of a clone or prefetch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// create the mirror of `url` at `path`
+    Mirror { url: String, path: Utf8PathBuf },
+    /// update the existing mirror at `path`
+    Update { path: Utf8PathBuf, reason: String },
+    /// use the mirror at `path` as it is
+    UseCached { path: Utf8PathBuf },
+    /// fetch `commit` by its id, if updating doesn't bring it in
+    FetchCommit { url: String, commit: String },
+    /// clone `url` into `target`, from the mirror at `mirror` if set
+    Clone {
+        url: String,
+        mirror: Option<Utf8PathBuf>,
+        target: Utf8PathBuf,
+    },
+    /// check out `commit` in `target`
+    Checkout { target: Utf8PathBuf, commit: String },
+    /// restrict the working tree of `target` to `paths`
+    SparseCheckout {
+        target: Utf8PathBuf,
+        paths: Vec<String>,
+    },
+    /// leave out the submodule at `path`
+    SkipSubmodule { path: Utf8PathBuf, reason: String },
+    /// the submodules of `url` are unknown before it is in the cache
+    UnknownSubmodules { url: String },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Action::Mirror { url, path } => write!(f, "mirror {url} into {path}"),
+            Action::Update { path, reason } => write!(f, "update {path} ({reason})"),
+            Action::UseCached { path } => write!(f, "use cached {path}"),
+            Action::FetchCommit { url, commit } => {
+                write!(
+                    f,
+                    "fetch {commit} from {url}, if updating doesn't bring it in"
+                )
+            }
+            Action::Clone {
+                url,
+                mirror: Some(mirror),
+                target,
+            } => write!(f, "clone {url} from {mirror} into {target}"),
+            Action::Clone {
+                url,
+                mirror: None,
+                target,
+            } => write!(f, "clone {url} into {target} (not cached)"),
+            Action::Checkout { target, commit } => write!(f, "check out {commit} in {target}"),
+            Action::SparseCheckout { target, paths } => {
+                write!(f, "check out only {} in {target}", paths.join(" "))
+            }
+            Action::SkipSubmodule { path, reason } => {
+                write!(f, "skip submodule `{path}` ({reason})")
+            }
+            Action::UnknownSubmodules { url } => {
+                write!(f, "look for submodules of {url} once it is cached")
+            }
+        }
+    }
+}
+
+impl GitCache {
+    /// Plans bringing the mirror of `url` up to date, returning the mirror if
+    /// it exists already.
+    fn plan_mirror(
+        &self,
+        url: &str,
+        update: bool,
+        commits: &[&str],
+        actions: &mut Vec<Action>,
+    ) -> Result<Option<GitCacheRepo>> {
+        let cache_repo = self.repo(url);
+        let path = cache_repo.repo().path().to_path_buf();
+
+        if !self.backend.is_initialized(cache_repo.repo())? {
+            actions.push(Action::Mirror {
+                url: url.to_string(),
+                path,
+            });
+            return Ok(None);
+        }
+
+        let mut missing = Vec::new();
+        for commit in commits {
+            if !cache_repo.has_commit(commit)? {
+                missing.push(*commit);
+            }
+        }
+        actions.push(match missing.as_slice() {
+            [commit] => Action::Update {
+                path,
+                reason: format!("{commit} is missing"),
+            },
+            [_, ..] => Action::Update {
+                path,
+                reason: format!("{} are missing", missing.join(", ")),
+            },
+            [] if update => Action::Update {
+                path,
+                reason: "update requested".into(),
+            },
+            [] => Action::UseCached { path },
+        });
+
+        Ok(Some(cache_repo))
+    }
+}
+
+impl GitCacheClonerBuilder {
+    pub fn plan(&mut self) -> Result<Vec<Action>, Error> {
+        self.build()
+            .expect("GitCacheCloner builder correctly set up")
+            .plan()
+    }
+}
+
+impl GitCacheCloner {
+    /// Returns what cloning would do, including submodules.
+    fn plan(&self) -> Result<Vec<Action>> {
+        let target_path = match self.cached {
+            true => self
+                .cache
+                .repo(&self.repository_url)
+                .target_path(self.target_path.as_ref())?,
+            false => target_path_from_url_maybe(&self.repository_url, self.target_path.as_ref())?,
+        };
+
+        let mut actions = Vec::new();
+        self.plan_clone(
+            &self.repository_url,
+            &target_path,
+            self.commit.as_deref(),
+            None,
+            &mut actions,
+        )?;
+        Ok(actions)
+    }
+
+    /// Plans cloning `url` into `target`, with `location` being the path
+    /// below the top-level repository and the nesting depth of submodules.
+    fn plan_clone(
+        &self,
+        url: &str,
+        target: &Utf8Path,
+        commit: Option<&str>,
+        location: Option<(Utf8PathBuf, usize)>,
+        actions: &mut Vec<Action>,
+    ) -> Result<()> {
+        let is_submodule = location.is_some();
+        let cached = match is_submodule {
+            true => self.cache.is_cacheable(url),
+            false => self.cached,
+        };
+
+        let mirror = if cached {
+            // with `remote`, submodule mirrors always get updated
+            let update = self.update || (is_submodule && self.remote_submodules);
+            let mirror = self
+                .cache
+                .plan_mirror(url, update, commit.as_slice(), actions)?;
+            actions.push(Action::Clone {
+                url: url.to_string(),
+                mirror: Some(self.cache.repo(url).repo().path().to_path_buf()),
+                target: target.to_path_buf(),
+            });
+            mirror
+        } else {
+            actions.push(Action::Clone {
+                url: url.to_string(),
+                mirror: None,
+                target: target.to_path_buf(),
+            });
+            None
+        };
+
+        if let Some(commit) = commit {
+            actions.push(Action::Checkout {
+                target: target.to_path_buf(),
+                commit: commit.to_string(),
+            });
+        }
+        if let (false, Some(paths)) = (is_submodule, &self.sparse_paths) {
+            actions.push(Action::SparseCheckout {
+                target: target.to_path_buf(),
+                paths: paths.clone(),
+            });
+        }
+
+        if !(self.recurse_all_submodules || self.recurse_submodules.is_some()) {
+            return Ok(());
+        }
+
+        // the submodules are only known if the commit to look at is cached
+        let mirror = match mirror {
+            Some(mirror) if commit.map_or(Ok(true), |commit| mirror.has_commit(commit))? => mirror,
+            _ => {
+                actions.push(Action::UnknownSubmodules {
+                    url: url.to_string(),
+                });
+                return Ok(());
+            }
+        };
+
+        let filter = SubmoduleFilter {
+            include: match self.recurse_all_submodules {
+                true => None,
+                false => self.recurse_submodules.clone(),
+            },
+            exclude: self.exclude_submodules.clone(),
+            max_depth: self.submodule_depth,
+        };
+        let (prefix, depth) = match &location {
+            Some((path, depth)) => (path.clone(), depth + 1),
+            None => (Utf8PathBuf::new(), 1),
+        };
+
+        let mut submodules = self
+            .cache
+            .backend
+            .list_submodules(mirror.repo(), Some(commit.unwrap_or("HEAD")))?;
+        submodule::apply_config(&self.clone_config(is_submodule)?, &mut submodules)?;
+        for submodule in submodules {
+            let path = prefix.join(&submodule.path);
+            if let Some(reason) = filter.skip_reason(&submodule, path.as_str(), depth) {
+                actions.push(Action::SkipSubmodule {
+                    path,
+                    reason: reason.into(),
+                });
+                continue;
+            }
+
+            let submodule_url = resolve_submodule_url(&absolute_local_url(url), &submodule.url);
+            // with `remote`, the tip of the submodule's branch gets checked out
+            let submodule_commit = match self.remote_submodules {
+                true => None,
+                false => Some(submodule.commit.as_str()),
+            };
+            self.plan_clone(
+                &submodule_url,
+                &target.join(&submodule.path),
+                submodule_commit,
+                Some((path, depth)),
+                actions,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the configuration a clone would get: the global one, with the
+    /// `--config` options of the top-level repository's clone.
+    fn clone_config(&self, is_submodule: bool) -> Result<gix_config::File<'static>> {
+        let mut config = gix_config::File::from_globals()?;
+
+        let mut args = self
+            .extra_clone_args
+            .iter()
+            .flatten()
+            .filter(|_| !is_submodule);
+        while let Some(arg) = args.next() {
+            let option = match arg.as_str() {
+                "-c" | "--config" => args.next().map(String::as_str),
+                _ => arg
+                    .strip_prefix("--config=")
+                    .or_else(|| arg.strip_prefix("-c")),
+            };
+            let Some((key, value)) = option.and_then(|option| option.split_once('=')) else {
+                continue;
+            };
+            let Some(key) = gix_config::KeyRef::parse_unvalidated(key.into()) else {
+                continue;
+            };
+            config
+                .section_mut_or_create_new(key.section_name, key.subsection_name)?
+                .push(key.value_name.to_string().try_into()?, Some(value.into()));
+        }
+
+        config.append(gix_config::File::from_environment_overrides()?);
+        Ok(config)
+    }
+}
+
+impl GitCachePrefetcherBuilder {
+    pub fn plan(&mut self) -> Result<Vec<Action>, Error> {
+        self.build()
+            .expect("GitCachePrefetcher builder correctly set up")
+            .plan()
+    }
+}
+
+impl GitCachePrefetcher {
+    /// Returns what prefetching would do, planning each mirror once for all
+    /// the commits queued for it, like the real thing.
+    fn plan(&self) -> Result<Vec<Action>> {
+        let mut actions = Vec::new();
+        let mut queue = self.requested().collect::<VecDeque<_>>();
+        // the commits planned for each mirror, with `None` for the mirror
+        let mut planned = HashMap::<Utf8PathBuf, HashSet<Option<String>>>::new();
+        let mirror_path =
+            |entry: &PrefetchEntry| self.cache.repo(&entry.url).repo().path().to_path_buf();
+
+        while let Some(entry) = queue.pop_front() {
+            let key = mirror_path(&entry);
+            let mut entries = vec![entry];
+            queue.retain(|queued| {
+                if mirror_path(queued) != key {
+                    return true;
+                }
+                entries.push(queued.clone());
+                false
+            });
+
+            let covered = planned.entry(key).or_default();
+            entries.retain(|entry| {
+                covered.insert(None)
+                    | entry
+                        .commit
+                        .as_ref()
+                        .is_some_and(|commit| covered.insert(Some(commit.clone())))
+            });
+            let Some(first) = entries.first() else {
+                continue;
+            };
+
+            let update = entries
+                .iter()
+                .any(|entry| entry.update.unwrap_or(self.update));
+            let commits = entries
+                .iter()
+                .filter_map(|entry| entry.commit.as_deref())
+                .collect::<Vec<_>>();
+
+            let mirror = self
+                .cache
+                .plan_mirror(&first.url, update, &commits, &mut actions)?;
+            if let Some(mirror) = &mirror {
+                for commit in &commits {
+                    if is_commit_id(commit) && !mirror.has_commit(commit)? {
+                        actions.push(Action::FetchCommit {
+                            url: first.url.clone(),
+                            commit: commit.to_string(),
+                        });
+                    }
+                }
+            }
+
+            let recurse = entries
+                .iter()
+                .filter(|entry| entry.recurse.unwrap_or(self.recurse_all_submodules))
+                .collect::<Vec<_>>();
+            match &mirror {
+                Some(mirror) => {
+                    for entry in recurse {
+                        queue.extend(submodule_entries(mirror, entry, &self.submodule_refs)?);
+                    }
+                }
+                None if !recurse.is_empty() => actions.push(Action::UnknownSubmodules {
+                    url: first.url.clone(),
+                }),
+                None => {}
+            }
+        }
+
+        Ok(actions)
+    }
+}
@@ -0,0 +1,305 @@
+//! Lists of repositories to prefetch (`git cache prefetch --from-file`).
+//!
+//! The format is one repository per line, optionally followed by options
+//! overriding the command line for that repository:
+//!
+//! ```text
+//! # comments and empty lines are ignored
+//! https://github.com/RIOT-OS/RIOT recurse
+//! https://github.com/zephyrproject-rtos/zephyr update no-recurse  # nightly
+//! https://github.com/embassy-rs/embassy@3f1a0e3c0d1f0a5d8f9c6f6b6f0e9a3c1b2d4e5f
+//! ```
+//!
+//! Options are `update` / `no-update` and `recurse` / `no-recurse`. Submodules
+//! inherit them from their superproject.
+//!
+//! Like on the command line, `<url>@<rev>` requires the mirror to contain
+//! `rev`, a commit id or ref name (see [`PrefetchEntry::parse()`]).
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Read;
+
+use anyhow::{bail, Context as _, Error, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefetchEntry {
+    pub url: String,
+    /// update the mirror if it exists, overriding `--update`
+    pub update: Option<bool>,
+    /// prefetch submodules, overriding `--recurse-submodules`
+    pub recurse: Option<bool>,
+    /// a commit (or ref) the mirror must contain, it gets updated if it
+    /// doesn't
+    pub commit: Option<String>,
+}
+
+impl PrefetchEntry {
+    pub fn new(url: String) -> Self {
+        Self {
+            url,
+            update: None,
+            recurse: None,
+            commit: None,
+        }
+    }
+
+    /// Parses `<url>[@<rev>]`.
+    ///
+    /// An `@` only separates a revision if what comes before it has a path,
+    /// so user names in URLs (`ssh://git@host/repo`, `git@host:repo`) are
+    /// left alone.
+    pub fn parse(spec: &str) -> Self {
+        if let Some((url, rev)) = spec.rsplit_once('@') {
+            let has_path = url
+                .split_once("://")
+                .map_or(url, |(_, rest)| rest)
+                .contains(['/', ':']);
+            if has_path && !rev.is_empty() && !rev.contains(':') {
+                return Self {
+                    commit: Some(rev.to_string()),
+                    ..Self::new(url.to_string())
+                };
+            }
+        }
+        Self::new(spec.to_string())
+    }
+}
+
+impl fmt::Display for PrefetchEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.commit {
+            Some(commit) => write!(f, "{}@{commit}", self.url),
+            None => write!(f, "{}", self.url),
+        }
+    }
+}
+
+/// What happened to the repositories of a prefetch run, including
+/// submodules.
+#[derive(Debug, Default)]
+pub struct PrefetchSummary {
+    pub succeeded: Vec<PrefetchEntry>,
+    pub failed: Vec<(PrefetchEntry, Error)>,
+    /// not attempted, because an earlier repository failed
+    pub skipped: Vec<PrefetchEntry>,
+    pub graph: PrefetchGraph,
+}
+
+impl PrefetchSummary {
+    pub fn total(&self) -> usize {
+        self.succeeded.len() + self.failed.len() + self.skipped.len()
+    }
+
+    /// Returns `true` if all repositories were prefetched.
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty() && self.skipped.is_empty()
+    }
+}
+
+/// The submodules discovered while prefetching.
+///
+/// Repositories are identified by the first URL seen for their mirror, so
+/// different spellings of the same URL are one node.
+#[derive(Debug, Default)]
+pub struct PrefetchGraph {
+    /// the requested repositories
+    pub roots: Vec<String>,
+    /// the submodules of each repository that has any
+    pub submodules: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl PrefetchGraph {
+    fn fmt_submodules<'a>(
+        &'a self,
+        f: &mut fmt::Formatter<'_>,
+        url: &str,
+        prefix: &str,
+        shown: &mut BTreeSet<&'a str>,
+    ) -> fmt::Result {
+        let Some(submodules) = self.submodules.get(url) else {
+            return Ok(());
+        };
+
+        for (n, submodule) in submodules.iter().enumerate() {
+            let (branch, indent) = if n + 1 == submodules.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            if shown.insert(submodule) {
+                writeln!(f, "{prefix}{branch}{submodule}")?;
+                self.fmt_submodules(f, submodule, &format!("{prefix}{indent}"), shown)?;
+            } else if self.submodules.contains_key(submodule) {
+                // its submodules were shown already
+                writeln!(f, "{prefix}{branch}{submodule} (*)")?;
+            } else {
+                writeln!(f, "{prefix}{branch}{submodule}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Shows the graph as a tree per requested repository, like `cargo tree`.
+impl fmt::Display for PrefetchGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut shown = BTreeSet::new();
+        for root in &self.roots {
+            writeln!(f, "{root}")?;
+            if shown.insert(root.as_str()) {
+                self.fmt_submodules(f, root, "", &mut shown)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a list of repositories from `path`, or from stdin if `path` is `-`.
+pub fn read_list(path: &str) -> Result<Vec<PrefetchEntry>> {
+    let content = if path == "-" {
+        let mut content = String::new();
+        std::io::stdin()
+            .read_to_string(&mut content)
+            .context("reading repositories from stdin")?;
+        content
+    } else {
+        std::fs::read_to_string(path).with_context(|| format!("reading '{path}'"))?
+    };
+
+    parse_list(&content).with_context(|| format!("parsing '{path}'"))
+}
+
+/// Parses a list of repositories, see the [module documentation](self).
+pub fn parse_list(content: &str) -> Result<Vec<PrefetchEntry>> {
+    let mut entries = Vec::new();
+
+    for (n, line) in content.lines().enumerate() {
+        let mut words = line
+            .split_whitespace()
+            .take_while(|word| !word.starts_with('#'));
+
+        let Some(spec) = words.next() else {
+            continue;
+        };
+
+        let mut entry = PrefetchEntry::parse(spec);
+        for option in words {
+            match option {
+                "update" => entry.update = Some(true),
+                "no-update" => entry.update = Some(false),
+                "recurse" => entry.recurse = Some(true),
+                "no-recurse" => entry.recurse = Some(false),
+                _ => bail!("line {}: unknown option `{option}`", n + 1),
+            }
+        }
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn revisions() {
+        for (spec, url, rev) in [
+            ("https://host/repo", "https://host/repo", None),
+            ("https://host/repo@main", "https://host/repo", Some("main")),
+            (
+                "https://host/repo.git@release/1.0",
+                "https://host/repo.git",
+                Some("release/1.0"),
+            ),
+            ("https://user@host/repo", "https://user@host/repo", None),
+            ("ssh://git@host/repo", "ssh://git@host/repo", None),
+            ("ssh://git@host/repo@v1", "ssh://git@host/repo", Some("v1")),
+            ("git@host:repo", "git@host:repo", None),
+            (
+                "git@host:org/repo@abc123",
+                "git@host:org/repo",
+                Some("abc123"),
+            ),
+            ("/srv/repo.git@", "/srv/repo.git@", None),
+        ] {
+            let entry = PrefetchEntry::parse(spec);
+            assert_eq!(entry.url, url, "{spec}");
+            assert_eq!(entry.commit.as_deref(), rev, "{spec}");
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let entries = parse_list(
+            "# repositories\n\
+             \n\
+             https://example.com/a.git\n\
+             \x20 git@example.com:b.git update no-recurse # comment\n\
+             https://example.com/c.git#not-a-comment recurse\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            entries,
+            [
+                PrefetchEntry::new("https://example.com/a.git".into()),
+                PrefetchEntry {
+                    url: "git@example.com:b.git".into(),
+                    update: Some(true),
+                    recurse: Some(false),
+                    commit: None,
+                },
+                PrefetchEntry {
+                    url: "https://example.com/c.git#not-a-comment".into(),
+                    update: None,
+                    recurse: Some(true),
+                    commit: None,
+                },
+            ]
+        );
+
+        let entries = parse_list("/srv/git/repo.git@v1.0 update\n").unwrap();
+        assert_eq!(entries[0].url, "/srv/git/repo.git");
+        assert_eq!(entries[0].commit.as_deref(), Some("v1.0"));
+
+        let e =
+            parse_list("https://example.com/a.git\nhttps://example.com/b.git force\n").unwrap_err();
+        assert_eq!(e.to_string(), "line 2: unknown option `force`");
+    }
+
+    #[test]
+    fn graph() {
+        let mut graph = PrefetchGraph {
+            roots: vec!["super".into(), "other".into()],
+            ..Default::default()
+        };
+        for (url, submodule) in [
+            ("super", "a"),
+            ("super", "b"),
+            ("a", "common"),
+            ("b", "common"),
+            ("common", "leaf"),
+            ("other", "leaf"),
+        ] {
+            graph
+                .submodules
+                .entry(url.into())
+                .or_default()
+                .insert(submodule.into());
+        }
+
+        assert_eq!(
+            graph.to_string(),
+            "super\n\
+             ├── a\n\
+             │   └── common\n\
+             │       └── leaf\n\
+             └── b\n\
+             \x20   └── common (*)\n\
+             other\n\
+             └── leaf\n"
+        );
+    }
+}
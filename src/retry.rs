@@ -0,0 +1,354 @@
+//! Retrying network operations that failed for transient reasons.
+//!
+//! Whether a failure is transient is decided by looking at git's error
+//! output: DNS and TLS errors, HTTP 5xx responses, failed or dropped
+//! connections and timeouts are retried, while e.g. authentication failures
+//! and missing repositories are not. Unknown errors are not retried either.
+
+use std::io::{IsTerminal, Read, Write};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// how often to retry after a transient failure
+    pub retries: u32,
+    /// delay before the first retry, doubling with every further retry
+    pub backoff: Duration,
+    /// time limit for each attempt
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 0,
+            backoff: Duration::from_secs(1),
+            timeout: None,
+        }
+    }
+}
+
+enum Outcome {
+    Success,
+    /// failed, with the reason if it is worth retrying
+    Failed(Option<&'static str>),
+}
+
+impl RetryPolicy {
+    /// Runs the commands built by `command` until one succeeds or fails for
+    /// a reason that isn't transient, or there are no retries left.
+    ///
+    /// `cleanup` runs after every failed attempt, e.g., to remove what's
+    /// left of an interrupted clone. `error` is the message of the returned
+    /// error.
+    pub(crate) fn run(
+        &self,
+        error: &str,
+        mut command: impl FnMut() -> Result<Command>,
+        mut cleanup: impl FnMut() -> Result<()>,
+    ) -> Result<()> {
+        let mut attempt = 0;
+        loop {
+            // the error output is only needed for deciding whether to retry
+            let capture = self.retries > 0;
+            let reason = match run_once(&mut command()?, self.timeout, capture)? {
+                Outcome::Success => return Ok(()),
+                Outcome::Failed(reason) => reason,
+            };
+
+            cleanup()?;
+
+            match reason {
+                Some(reason) if attempt < self.retries => {
+                    let delay = self.backoff.saturating_mul(2u32.saturating_pow(attempt));
+                    attempt += 1;
+                    println!(
+                        "git-cache: {error} ({reason}), retrying in {}s ({attempt}/{})...",
+                        delay.as_secs_f32(),
+                        self.retries
+                    );
+                    thread::sleep(delay);
+                }
+                Some(reason) => return Err(anyhow!("{error} ({reason})")),
+                None => return Err(anyhow!("{error}")),
+            }
+        }
+    }
+}
+
+/// Returns `--progress` if git's error output would go to a terminal.
+///
+/// git only reports progress on a terminal by default, which it doesn't see
+/// when [`RetryPolicy::run`] captures the error output.
+pub(crate) fn progress_arg() -> Option<&'static str> {
+    std::io::stderr().is_terminal().then_some("--progress")
+}
+
+/// Runs `command`, passing its error output through. With `capture`, a copy
+/// is kept for classifying failures.
+fn run_once(command: &mut Command, timeout: Option<Duration>, capture: bool) -> Result<Outcome> {
+    if capture {
+        command.stderr(Stdio::piped());
+    }
+    // git runs the transports (e.g., `git-remote-https`) as separate
+    // processes, which need to be killed on timeouts as well. That needs a
+    // process group of their own, which would take git out of the
+    // terminal's foreground process group, leaving it without Ctrl-C and
+    // hanging on prompts, so it's only done without a terminal.
+    let process_group = cfg!(unix) && timeout.is_some() && !std::io::stdin().is_terminal();
+    #[cfg(unix)]
+    if process_group {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+    let mut child = command.spawn()?;
+
+    let reader = child.stderr.take().map(|mut stderr| {
+        thread::spawn(move || {
+            let mut output = Vec::new();
+            let mut buf = [0u8; 4096];
+            while let Ok(n @ 1..) = stderr.read(&mut buf) {
+                let _ = std::io::stderr().write_all(&buf[..n]);
+                output.extend_from_slice(&buf[..n]);
+            }
+            output
+        })
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            kill(&mut child, process_group);
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    Ok(match status {
+        Some(status) if status.success() => Outcome::Success,
+        Some(_) => match reader {
+            Some(reader) => {
+                let output = reader.join().unwrap_or_default();
+                Outcome::Failed(transient_reason(&String::from_utf8_lossy(&output)))
+            }
+            None => Outcome::Failed(None),
+        },
+        // processes that left the process group might still hold the pipe,
+        // don't wait for them
+        None => Outcome::Failed(Some("timeout")),
+    })
+}
+
+/// Kills `child`, along with the processes it spawned if it leads
+/// `process_group`.
+fn kill(child: &mut Child, process_group: bool) {
+    if process_group {
+        // SAFETY: `killpg` has no memory safety requirements
+        #[cfg(unix)]
+        if unsafe { libc::killpg(child.id() as libc::pid_t, libc::SIGKILL) } == 0 {
+            return;
+        }
+    }
+    let _ = child.kill();
+}
+
+/// Returns why a command failed, if the error output indicates a transient
+/// failure.
+fn transient_reason(stderr: &str) -> Option<&'static str> {
+    const PERMANENT: &[&str] = &[
+        "authentication failed",
+        "could not read username",
+        "could not read password",
+        "permission denied",
+        "repository not found",
+        "does not appear to be a git repository",
+        "returned error: 401",
+        "returned error: 403",
+        "returned error: 404",
+        "certificate",
+    ];
+    const TRANSIENT: &[(&str, &str)] = &[
+        ("could not resolve host", "DNS"),
+        ("temporary failure in name resolution", "DNS"),
+        ("name or service not known", "DNS"),
+        ("gnutls", "TLS"),
+        ("ssl_", "TLS"),
+        ("tls connection", "TLS"),
+        ("returned error: 5", "HTTP 5xx"),
+        ("http 5", "HTTP 5xx"),
+        ("connection reset", "connection reset"),
+        ("connection refused", "connection refused"),
+        ("failed to connect", "connection failed"),
+        ("connection timed out", "timeout"),
+        ("operation timed out", "timeout"),
+        ("remote end hung up unexpectedly", "connection lost"),
+        ("early eof", "connection lost"),
+        ("unexpected disconnect", "connection lost"),
+        ("rpc failed", "connection lost"),
+    ];
+
+    let stderr = stderr.to_ascii_lowercase();
+    if PERMANENT.iter().any(|pattern| stderr.contains(pattern)) {
+        return None;
+    }
+    TRANSIENT
+        .iter()
+        .find(|(pattern, _)| stderr.contains(pattern))
+        .map(|(_, reason)| *reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    fn policy(timeout: Option<Duration>) -> RetryPolicy {
+        RetryPolicy {
+            retries: 2,
+            backoff: Duration::ZERO,
+            timeout,
+        }
+    }
+
+    fn sh(script: &str) -> Result<Command> {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(script);
+        Ok(command)
+    }
+
+    #[test]
+    fn classification() {
+        for (stderr, reason) in [
+            (
+                "fatal: unable to access 'https://x/': Could not resolve host: x",
+                Some("DNS"),
+            ),
+            (
+                "fatal: unable to access 'https://x/': gnutls_handshake() failed",
+                Some("TLS"),
+            ),
+            (
+                "fatal: unable to access 'https://x/': The requested URL returned error: 502",
+                Some("HTTP 5xx"),
+            ),
+            (
+                "error: RPC failed; curl 56 Recv failure: Connection reset by peer",
+                Some("connection reset"),
+            ),
+            (
+                "fatal: unable to access 'https://x/': OpenSSL SSL_read: error:0A000126",
+                Some("TLS"),
+            ),
+            (
+                "fatal: unable to access 'https://x/': SSL certificate problem: self-signed certificate",
+                None,
+            ),
+            ("fatal: Authentication failed for 'https://x/'", None),
+            (
+                "fatal: unable to access 'https://x/': The requested URL returned error: 404",
+                None,
+            ),
+            ("remote: Repository not found.", None),
+            ("fatal: '/x' does not appear to be a git repository", None),
+            ("fatal: something else", None),
+        ] {
+            assert_eq!(transient_reason(stderr), reason, "{stderr}");
+        }
+    }
+
+    #[test]
+    fn transient_failures_are_retried() {
+        let attempts = Cell::new(0);
+        let result = policy(None).run(
+            "error",
+            || {
+                attempts.set(attempts.get() + 1);
+                sh("echo 'fatal: Could not resolve host: x' >&2; exit 128")
+            },
+            || Ok(()),
+        );
+        assert_eq!(result.unwrap_err().to_string(), "error (DNS)");
+        assert_eq!(attempts.get(), 3);
+
+        let attempts = Cell::new(0);
+        policy(None)
+            .run(
+                "error",
+                || {
+                    attempts.set(attempts.get() + 1);
+                    sh(if attempts.get() == 1 {
+                        "echo 'fatal: early EOF' >&2; exit 128"
+                    } else {
+                        "true"
+                    })
+                },
+                || Ok(()),
+            )
+            .unwrap();
+        assert_eq!(attempts.get(), 2);
+    }
+
+    #[test]
+    fn permanent_failures_are_not_retried() {
+        let cleanups = Cell::new(0);
+        let result = policy(None).run(
+            "error",
+            || sh("echo 'fatal: Authentication failed' >&2; exit 128"),
+            || {
+                cleanups.set(cleanups.get() + 1);
+                Ok(())
+            },
+        );
+        assert_eq!(result.unwrap_err().to_string(), "error");
+        assert_eq!(cleanups.get(), 1);
+    }
+
+    #[test]
+    fn attempts_time_out() {
+        let started = Instant::now();
+        let result =
+            policy(Some(Duration::from_millis(100))).run("error", || sh("sleep 10"), || Ok(()));
+        assert_eq!(result.unwrap_err().to_string(), "error (timeout)");
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn timeouts_kill_spawned_processes() {
+        // only done without a terminal, see `run_once()`
+        if std::io::stdin().is_terminal() {
+            return;
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("marker");
+        let script = format!("(sleep 1; touch '{}') & wait", marker.display());
+        let result =
+            policy(Some(Duration::from_millis(100))).run("error", || sh(&script), || Ok(()));
+        assert_eq!(result.unwrap_err().to_string(), "error (timeout)");
+
+        thread::sleep(Duration::from_secs(2));
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn failures_without_retries() {
+        let policy = RetryPolicy::default();
+        let result = policy.run(
+            "error",
+            || sh("echo 'fatal: early EOF' >&2; exit 128"),
+            || Ok(()),
+        );
+        assert_eq!(result.unwrap_err().to_string(), "error");
+        policy.run("error", || sh("true"), || Ok(())).unwrap();
+    }
+}
@@ -0,0 +1,219 @@
+//! Work queue for cloning trees of repositories.
+//!
+//! Jobs run on a fixed set of worker threads and may queue more jobs, e.g., a
+//! submodule clone queues the clones of its own submodules. That way, a whole
+//! tree of clones shares the same workers, no matter how deep it is.
+//!
+//! Network operations (creating and updating mirrors, direct clones) also
+//! need one of a limited number of permits ([`Scheduler::network`]), so
+//! `--jobs` bounds the number of concurrent fetches, while local work (clones
+//! from the cache, checkouts) continues on the other workers.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use anyhow::{anyhow, Error, Result};
+use crossbeam::channel::{Receiver, Sender};
+
+type Job = Box<dyn FnOnce(&Scheduler) -> Result<()> + Send>;
+
+enum Task {
+    Run(Job),
+    Stop,
+}
+
+#[derive(Clone)]
+pub(crate) struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    sender: Sender<Task>,
+    state: Mutex<State>,
+    /// signalled whenever a job finishes or a network permit is returned
+    changed: Condvar,
+    network_jobs: usize,
+}
+
+#[derive(Default)]
+struct State {
+    /// jobs queued or running
+    pending: usize,
+    /// network permits in use
+    network: usize,
+    /// the first error of any job
+    error: Option<Error>,
+}
+
+impl Scheduler {
+    /// Runs `f`, which queues the initial jobs, and waits for all jobs to
+    /// finish, including those queued by other jobs.
+    ///
+    /// Up to `network_jobs` network operations run at the same time. After
+    /// the first error, jobs that didn't start yet are skipped, and the error
+    /// is returned.
+    pub(crate) fn run(network_jobs: usize, f: impl FnOnce(&Scheduler) -> Result<()>) -> Result<()> {
+        let network_jobs = network_jobs.max(1);
+        // local work is limited by the CPUs, but waiting for a network permit
+        // must not keep the others from making progress
+        let workers = thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .max(network_jobs + 1);
+
+        let (sender, receiver) = crossbeam::channel::unbounded::<Task>();
+        let scheduler = Scheduler {
+            shared: Arc::new(Shared {
+                sender,
+                state: Mutex::new(State::default()),
+                changed: Condvar::new(),
+                network_jobs,
+            }),
+        };
+
+        let handles = (0..workers)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let scheduler = scheduler.clone();
+                thread::spawn(move || scheduler.work(receiver))
+            })
+            .collect::<Vec<_>>();
+
+        if let Err(e) = f(&scheduler) {
+            scheduler.failed(e);
+        }
+
+        {
+            let state = scheduler.shared.state.lock().unwrap();
+            let _state = scheduler
+                .shared
+                .changed
+                .wait_while(state, |state| state.pending > 0)
+                .unwrap();
+        }
+
+        for _ in &handles {
+            let _ = scheduler.shared.sender.send(Task::Stop);
+        }
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        match scheduler.shared.state.lock().unwrap().error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Queues `job`.
+    pub(crate) fn spawn(&self, job: impl FnOnce(&Scheduler) -> Result<()> + Send + 'static) {
+        self.shared.state.lock().unwrap().pending += 1;
+        let _ = self.shared.sender.send(Task::Run(Box::new(job)));
+    }
+
+    /// Runs the network operation `f` once a network permit is available.
+    ///
+    /// Callers must not hold a cache lock while waiting here, as that would
+    /// hold up all other jobs needing that lock. Take the lock inside `f`
+    /// instead.
+    pub(crate) fn network<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        {
+            let state = self.shared.state.lock().unwrap();
+            let mut state = self
+                .shared
+                .changed
+                .wait_while(state, |state| state.network >= self.shared.network_jobs)
+                .unwrap();
+            state.network += 1;
+        }
+
+        scopeguard::defer! {
+            self.shared.state.lock().unwrap().network -= 1;
+            self.shared.changed.notify_all();
+        }
+
+        f()
+    }
+
+    fn work(&self, receiver: Receiver<Task>) {
+        for task in receiver.iter() {
+            let Task::Run(job) = task else {
+                break;
+            };
+
+            let skip = self.shared.state.lock().unwrap().error.is_some();
+            if !skip {
+                let result = std::panic::catch_unwind(AssertUnwindSafe(|| job(self)))
+                    .unwrap_or_else(|_| Err(anyhow!("job panicked")));
+                if let Err(e) = result {
+                    self.failed(e);
+                }
+            }
+
+            self.shared.state.lock().unwrap().pending -= 1;
+            self.shared.changed.notify_all();
+        }
+    }
+
+    fn failed(&self, e: Error) {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.error.is_none() {
+            state.error = Some(e);
+        } else {
+            println!("git-cache: error: {e:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn nested_jobs_respect_network_limit() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let done = Arc::new(AtomicUsize::new(0));
+
+        fn fetch(
+            scheduler: &Scheduler,
+            depth: usize,
+            counters: (Arc<AtomicUsize>, Arc<AtomicUsize>, Arc<AtomicUsize>),
+        ) -> Result<()> {
+            let (running, max_running, done) = &counters;
+            scheduler.network(|| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                max_running.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(std::time::Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Ok(())
+            })?;
+            done.fetch_add(1, Ordering::SeqCst);
+
+            if depth < 3 {
+                for _ in 0..3 {
+                    let counters = counters.clone();
+                    scheduler.spawn(move |scheduler| fetch(scheduler, depth + 1, counters));
+                }
+            }
+            Ok(())
+        }
+
+        let counters = (running, max_running.clone(), done.clone());
+        Scheduler::run(2, |scheduler| fetch(scheduler, 0, counters)).unwrap();
+
+        assert_eq!(done.load(Ordering::SeqCst), 1 + 3 + 9 + 27);
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+    }
+
+    #[test]
+    fn first_error_is_returned() {
+        let result = Scheduler::run(1, |scheduler| {
+            scheduler.spawn(|_| Err(anyhow!("first")));
+            Ok(())
+        });
+        assert_eq!(result.unwrap_err().to_string(), "first");
+    }
+}
@@ -0,0 +1,134 @@
+//! Support for cache directories shared by multiple users of a Unix group.
+//!
+//! In shared mode, directories below the cache base directory are created
+//! group-writable with the setgid bit set (so new files inherit the cache's
+//! group), mirrors are created with `core.sharedRepository=group` and lock
+//! files are made group-writable regardless of the creating user's umask.
+
+use std::fs::File;
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+/// Mode bits required on directories of a shared cache.
+#[cfg(unix)]
+pub(crate) const SHARED_DIR_MODE: u32 = 0o2070;
+
+/// Mode bits required on lock files of a shared cache.
+#[cfg(unix)]
+pub(crate) const SHARED_FILE_MODE: u32 = 0o060;
+
+/// Creates `path` and all missing parents.
+///
+/// If `shared` is set, all directories that did not exist before get
+/// group permissions and the setgid bit.
+pub(crate) fn create_dir_all(path: &Utf8Path, shared: bool) -> Result<()> {
+    let missing = path
+        .ancestors()
+        .take_while(|dir| !dir.as_str().is_empty() && !dir.exists())
+        .map(|dir| dir.to_path_buf())
+        .collect::<Vec<_>>();
+
+    std::fs::create_dir_all(path).with_context(|| format!("creating directory '{path}'"))?;
+
+    if shared {
+        for dir in missing {
+            add_mode(&dir, SHARED_DIR_MODE)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates (or truncates) a lock file, group-writable if `shared` is set.
+pub(crate) fn create_lock_file(path: &Utf8Path, shared: bool) -> Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.read(true).write(true).create(true).truncate(true);
+
+    #[cfg(unix)]
+    if shared {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o664);
+    }
+
+    let file = options
+        .open(path)
+        .with_context(|| format!("creating lock file \"{path}\""))?;
+
+    // The umask might have removed group write permissions, so set them
+    // explicitly. Only the owner may do that, but then again, only the owner
+    // can have created the file with the wrong permissions.
+    if shared {
+        match add_mode(path, SHARED_FILE_MODE) {
+            Err(e) if !is_permission_denied(&e) => return Err(e),
+            _ => (),
+        }
+    }
+
+    Ok(file)
+}
+
+fn is_permission_denied(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::PermissionDenied)
+}
+
+#[cfg(unix)]
+fn add_mode(path: &Utf8Path, mode: u32) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = path.metadata()?.permissions();
+    permissions.set_mode(permissions.mode() | mode);
+    std::fs::set_permissions(path, permissions)
+        .with_context(|| format!("setting permissions of '{path}'"))
+}
+
+#[cfg(not(unix))]
+fn add_mode(_path: &Utf8Path, _mode: u32) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::*;
+
+    fn mode(path: &Utf8Path) -> u32 {
+        path.metadata().unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn shared_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Utf8Path::from_path(dir.path()).unwrap();
+        std::fs::set_permissions(base, std::fs::Permissions::from_mode(0o700)).unwrap();
+
+        create_dir_all(&base.join("a/b"), true).unwrap();
+        create_dir_all(&base.join("c"), false).unwrap();
+
+        // existing directories are left alone
+        assert_eq!(mode(base), 0o700);
+        for path in ["a", "a/b"] {
+            let mode = mode(&base.join(path));
+            assert_eq!(mode & SHARED_DIR_MODE, SHARED_DIR_MODE, "{path}: {mode:o}");
+        }
+        assert_eq!(mode(&base.join("c")) & 0o2000, 0);
+    }
+
+    #[test]
+    fn shared_lock_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = Utf8Path::from_path(dir.path()).unwrap();
+
+        let lock = base.join("repo.git.lock");
+        create_lock_file(&lock, true).unwrap();
+        assert_eq!(mode(&lock) & SHARED_FILE_MODE, SHARED_FILE_MODE);
+
+        // the permissions are repaired for existing lock files, too
+        std::fs::set_permissions(&lock, std::fs::Permissions::from_mode(0o600)).unwrap();
+        create_lock_file(&lock, true).unwrap();
+        assert_eq!(mode(&lock), 0o660);
+    }
+}
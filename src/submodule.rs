@@ -0,0 +1,236 @@
+//! Git's rules for which submodules get cloned.
+//!
+//! A submodule is skipped if it is inactive or its update mode is `none`.
+//! Whether it is active is decided like git does:
+//!
+//! 1. `submodule.<name>.active`, if set,
+//! 2. otherwise, whether `submodule.active` (a list of pathspecs) matches
+//!    the submodule's path, if set,
+//! 3. otherwise, it is active (like `git submodule update --init` would
+//!    make it).
+//!
+//! On top of that, a [`SubmoduleFilter`] selects submodules by their path
+//! below the top-level repository.
+
+use anyhow::Result;
+
+use crate::SubmoduleSpec;
+
+/// Which submodules to clone, applied through the whole recursion tree.
+///
+/// Paths are relative to the top-level repository.
+#[derive(Debug, Clone, Default)]
+pub struct SubmoduleFilter {
+    /// pathspecs of submodules to clone, `None` meaning all active ones
+    ///
+    /// Submodules inside matched paths are included, as are those on the
+    /// way to a (literal) path.
+    pub include: Option<Vec<String>>,
+    /// globs of submodules not to clone, along with their submodules
+    pub exclude: Vec<String>,
+    /// how many levels of submodules to clone, `None` meaning all
+    pub max_depth: Option<usize>,
+}
+
+impl SubmoduleFilter {
+    /// Returns `true` if the submodule at `path`, nested `depth` levels deep
+    /// (1 for submodules of the top-level repository), should be cloned.
+    pub(crate) fn matches(&self, path: &str, depth: usize) -> bool {
+        if self.max_depth.is_some_and(|max_depth| depth > max_depth) {
+            return false;
+        }
+
+        if self
+            .exclude
+            .iter()
+            .any(|pattern| pattern_matches(pattern, path))
+        {
+            return false;
+        }
+
+        match &self.include {
+            None => true,
+            Some(include) => {
+                pathspec_matches(include, path)
+                    || include.iter().any(|pathspec| {
+                        pathspec
+                            .strip_prefix(path)
+                            .is_some_and(|rest| rest.starts_with('/'))
+                    })
+            }
+        }
+    }
+
+    /// Returns why `submodule`, at `path` below the top-level repository and
+    /// nested `depth` levels deep, doesn't get cloned, or `None` if it does.
+    pub(crate) fn skip_reason(
+        &self,
+        submodule: &SubmoduleSpec,
+        path: &str,
+        depth: usize,
+    ) -> Option<&'static str> {
+        if !self.matches(path, depth) {
+            Some("filtered")
+        // an explicit list of paths overrides what's configured as active,
+        // like `git clone --recurse-submodules=<pathspec>` does
+        } else if self.include.is_none() && !submodule.active {
+            Some("inactive")
+        } else if !submodule.is_updated() {
+            Some("update = none")
+        } else if submodule.commit.is_empty() {
+            Some("no commit recorded")
+        } else {
+            None
+        }
+    }
+}
+
+/// Sets whether `submodules` are active and their update modes according to
+/// the repository configuration `config`.
+pub(crate) fn apply_config(
+    config: &gix_config::File,
+    submodules: &mut [SubmoduleSpec],
+) -> Result<()> {
+    for submodule in submodules {
+        submodule.active = is_active(config, &submodule.name, &submodule.path)?;
+        submodule.update = update_mode(config, &submodule.name, submodule.update.as_deref());
+    }
+    Ok(())
+}
+
+/// Returns `true` if the submodule `name` at `path` is active according to
+/// the repository configuration `config`.
+fn is_active(config: &gix_config::File, name: &str, path: &str) -> Result<bool> {
+    if let Some(active) = config.boolean_by("submodule", Some(name.into()), "active") {
+        return Ok(active?);
+    }
+
+    let pathspecs = config
+        .strings("submodule.active")
+        .unwrap_or_default()
+        .into_iter()
+        .map(|pathspec| pathspec.to_string())
+        .collect::<Vec<_>>();
+    if !pathspecs.is_empty() {
+        return Ok(pathspec_matches(&pathspecs, path));
+    }
+
+    Ok(true)
+}
+
+/// Returns the update mode of the submodule `name`, with the one from the
+/// repository configuration `config` overriding `.gitmodules`.
+fn update_mode(
+    config: &gix_config::File,
+    name: &str,
+    gitmodules_update: Option<&str>,
+) -> Option<String> {
+    config
+        .string_by("submodule", Some(name.into()), "update")
+        .map(|update| update.to_string())
+        .or(gitmodules_update.map(str::to_string))
+}
+
+/// Returns `true` if `path` is matched by `pathspecs`.
+///
+/// Supported are literal paths (matching everything below them), wildcards
+/// (`*` also matching `/`, like git's default) and exclusions (`:!`, `:^`
+/// or `:(exclude)`). Without positive pathspecs, everything is included.
+pub(crate) fn pathspec_matches(pathspecs: &[String], path: &str) -> bool {
+    let mut included = None;
+
+    for pathspec in pathspecs {
+        let (exclude, pattern) = match pathspec
+            .strip_prefix(":!")
+            .or_else(|| pathspec.strip_prefix(":^"))
+            .or_else(|| pathspec.strip_prefix(":(exclude)"))
+        {
+            Some(pattern) => (true, pattern),
+            None => (false, pathspec.as_str()),
+        };
+
+        if exclude {
+            if pattern_matches(pattern, path) {
+                return false;
+            }
+        } else if !included.unwrap_or(false) {
+            included = Some(pattern_matches(pattern, path));
+        }
+    }
+
+    included.unwrap_or(true)
+}
+
+fn pattern_matches(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.trim_end_matches('/');
+
+    if pattern.is_empty() || pattern == "." {
+        return true;
+    }
+
+    if path == pattern
+        || path
+            .strip_prefix(pattern)
+            .is_some_and(|rest| rest.starts_with('/'))
+    {
+        return true;
+    }
+
+    glob::Pattern::new(pattern).is_ok_and(|pattern| {
+        pattern.matches_with(
+            path,
+            glob::MatchOptions {
+                case_sensitive: true,
+                require_literal_separator: false,
+                require_literal_leading_dot: false,
+            },
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pathspecs: &[&str], path: &str) -> bool {
+        let pathspecs = pathspecs.iter().map(|p| p.to_string()).collect::<Vec<_>>();
+        pathspec_matches(&pathspecs, path)
+    }
+
+    #[test]
+    fn pathspecs() {
+        assert!(matches(&["."], "lib/sub"));
+        assert!(matches(&["lib"], "lib/sub"));
+        assert!(matches(&["lib/"], "lib/sub"));
+        assert!(!matches(&["li"], "lib/sub"));
+        assert!(matches(&["*/sub"], "lib/sub"));
+        assert!(matches(&["*sub"], "lib/deep/sub"));
+        assert!(!matches(&["other", "*/x"], "lib/sub"));
+        assert!(matches(&["other", "lib/sub"], "lib/sub"));
+        assert!(!matches(&[".", ":!lib"], "lib/sub"));
+        assert!(matches(&[".", ":(exclude)lib/other"], "lib/sub"));
+        assert!(!matches(&[":^*/sub"], "lib/sub"));
+        assert!(matches(&[":^*/other"], "lib/sub"));
+    }
+
+    #[test]
+    fn filter() {
+        let filter = SubmoduleFilter {
+            include: Some(vec!["lib/a/deep".into(), "ext".into()]),
+            exclude: vec!["*/testdata".into()],
+            max_depth: Some(3),
+        };
+
+        assert!(filter.matches("lib", 1));
+        assert!(filter.matches("lib/a", 2));
+        assert!(filter.matches("lib/a/deep", 3));
+        assert!(!filter.matches("lib/b", 2));
+        assert!(filter.matches("ext", 1));
+        assert!(filter.matches("ext/x", 2));
+        assert!(!filter.matches("ext/x/testdata", 3));
+        assert!(!filter.matches("ext/x/y/z", 4));
+
+        let all = SubmoduleFilter::default();
+        assert!(all.matches("a/b/c/d/e", 5));
+    }
+}
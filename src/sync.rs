@@ -0,0 +1,276 @@
+//! Cloning or updating all repositories of a manifest (`git cache sync`).
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Error, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::manifest::{ManifestEntry, SubmodulePolicy};
+use crate::scheduler::Scheduler;
+use crate::submodule::SubmoduleFilter;
+use crate::{CanCloneInto, GitCache, GitRepo, SubmoduleOptions};
+
+#[derive(Builder, Clone)]
+pub struct GitCacheSyncer {
+    cache: GitCache,
+    entries: Vec<ManifestEntry>,
+    /// directory that manifest paths are relative to
+    #[builder(default = "Utf8PathBuf::from(\".\")")]
+    workspace: Utf8PathBuf,
+    #[builder(default)]
+    update: bool,
+    #[builder(default)]
+    jobs: Option<usize>,
+}
+
+impl GitCacheSyncerBuilder {
+    pub fn do_sync(&mut self) -> Result<(), Error> {
+        self.build()
+            .expect("GitCacheSyncer builder correctly set up")
+            .do_sync()
+    }
+}
+
+impl GitCache {
+    pub fn syncer(&self) -> GitCacheSyncerBuilder {
+        let mut syncer = GitCacheSyncerBuilder::default();
+        syncer.cache(self.clone());
+        syncer
+    }
+}
+
+impl GitCacheSyncer {
+    /// Clones all manifest entries that don't exist yet and brings existing
+    /// checkouts to the requested state, so running this again is cheap.
+    ///
+    /// All repositories and their submodules share one [`Scheduler`].
+    fn do_sync(&self) -> Result<(), Error> {
+        let syncer = Arc::new(self.clone());
+
+        Scheduler::run(self.jobs.unwrap_or(1), |scheduler| {
+            for entry in &self.entries {
+                let syncer = syncer.clone();
+                let entry = entry.clone();
+                scheduler.spawn(move |scheduler| {
+                    let target_path = syncer.workspace.join(entry.target_path());
+                    syncer
+                        .sync_entry(&entry, &target_path, scheduler)
+                        .map_err(|e| anyhow!("syncing {}: {e:#}", entry.url))
+                });
+            }
+            Ok(())
+        })?;
+
+        println!(
+            "git-cache: finished syncing {} repositories.",
+            self.entries.len()
+        );
+
+        Ok(())
+    }
+
+    fn sync_entry(
+        &self,
+        entry: &ManifestEntry,
+        target_path: &Utf8Path,
+        scheduler: &Scheduler,
+    ) -> Result<()> {
+        if target_path.is_clone_target()? {
+            println!("git-cache: cloning {} into {target_path}...", entry.url);
+            self.clone_entry(entry, target_path, scheduler)
+        } else {
+            println!("git-cache: updating {target_path}...");
+            self.update_entry(entry, target_path, scheduler)
+        }
+    }
+
+    fn clone_entry(
+        &self,
+        entry: &ManifestEntry,
+        target_path: &Utf8Path,
+        scheduler: &Scheduler,
+    ) -> Result<()> {
+        let mut extra_clone_args = Vec::new();
+        if let Some(branch) = &entry.branch {
+            extra_clone_args.extend(["--branch".into(), branch.clone()]);
+        }
+        if entry.commit.is_some() {
+            extra_clone_args.push("--no-checkout".into());
+        }
+        if entry.sparse.is_some() {
+            extra_clone_args.push("--sparse".into());
+        }
+
+        let mut cloner = self.cache.cloner();
+        cloner
+            .repository_url(entry.url.clone())
+            .target_path(Some(target_path.to_path_buf()))
+            .commit(entry.commit.clone())
+            .sparse_paths(entry.sparse.clone())
+            .update(self.update || entry.update)
+            .extra_clone_args(Some(extra_clone_args))
+            .scheduler(Some(scheduler.clone()));
+
+        match &entry.submodules {
+            SubmodulePolicy::None => {}
+            SubmodulePolicy::All => {
+                cloner.recurse_all_submodules(true);
+            }
+            SubmodulePolicy::Paths(paths) => {
+                cloner.recurse_submodules(Some(paths.clone()));
+            }
+        }
+
+        cloner.do_clone()
+    }
+
+    /// Updates an existing checkout from the cache.
+    ///
+    /// Branches are only fast-forwarded, so local work is never lost.
+    fn update_entry(
+        &self,
+        entry: &ManifestEntry,
+        target_path: &Utf8Path,
+        scheduler: &Scheduler,
+    ) -> Result<()> {
+        let target_repo = GitRepo {
+            path: target_path.to_path_buf(),
+            safe_directory: false,
+        };
+
+        if !target_repo.is_initialized()? {
+            bail!("{target_path} exists but is not a git repository");
+        }
+
+        let origin = target_repo.get_config("remote.origin.url")?;
+        if origin.as_deref() != Some(entry.url.as_str()) {
+            bail!(
+                "{target_path} is a clone of {}, not {}",
+                origin.as_deref().unwrap_or("<no origin>"),
+                entry.url
+            );
+        }
+
+        let cache_repo = self.cache.repo(&entry.url);
+        let update = self.update || entry.update;
+        let mut lock = cache_repo.lockfile()?;
+        let mut update_mirror = || -> Result<()> {
+            let _lock = lock.write()?;
+            if !cache_repo.mirror()? {
+                let missing_commit = match &entry.commit {
+                    Some(commit) => !cache_repo.has_commit(commit)?,
+                    None => false,
+                };
+                if update || missing_commit {
+                    println!("git-cache: updating cache for {}...", entry.url);
+                    cache_repo.update()?;
+                }
+            }
+            Ok(())
+        };
+        // get the network permit before the lock, see `GitCacheCloner`
+        if cache_repo.may_fetch(update, entry.commit.as_deref())? {
+            scheduler.network(update_mirror)?;
+        } else {
+            update_mirror()?;
+        }
+        {
+            let _lock = lock.read()?;
+            cache_repo.fetch_into(&target_repo, "origin", false)?;
+        }
+
+        if let Some(commit) = &entry.commit {
+            target_repo.set_config("advice.detachedHead", "false")?;
+            target_repo.checkout(commit)?;
+        } else if let Some(branch) = &entry.branch {
+            if target_repo.has_ref(&format!("refs/remotes/origin/{branch}"))? {
+                target_repo.checkout_branch(branch)?;
+            } else {
+                // `git clone --branch` also accepts tags
+                target_repo.set_config("advice.detachedHead", "false")?;
+                target_repo.checkout(branch)?;
+            }
+        } else if let Some(branch) = target_repo.current_branch()? {
+            // follow the branch that got cloned (usually the default branch)
+            if target_repo.has_ref(&format!("refs/remotes/origin/{branch}"))? {
+                target_repo.checkout_branch(&branch)?;
+            }
+        }
+
+        if let Some(sparse_paths) = &entry.sparse {
+            target_repo.sparse_checkout(sparse_paths)?;
+        }
+
+        let filter = SubmoduleFilter {
+            include: match &entry.submodules {
+                SubmodulePolicy::None => return Ok(()),
+                SubmodulePolicy::All => None,
+                SubmodulePolicy::Paths(paths) => Some(paths.clone()),
+            },
+            ..Default::default()
+        };
+        self.update_submodules(&target_repo, &entry.url, &filter, None, update, scheduler)
+    }
+
+    /// Clones or updates the submodules of the checkout `repo` that `filter`
+    /// selects, and their submodules in turn.
+    ///
+    /// `location` is as for [`GitCache::submodules()`]. Like with a clone,
+    /// nested submodules get the filter and `update` of the manifest entry.
+    fn update_submodules(
+        &self,
+        repo: &GitRepo,
+        url: &str,
+        filter: &SubmoduleFilter,
+        location: Option<(Utf8PathBuf, usize)>,
+        update: bool,
+        scheduler: &Scheduler,
+    ) -> Result<()> {
+        let options = SubmoduleOptions {
+            filter: filter.clone(),
+            update,
+            ..Default::default()
+        };
+        let (prefix, depth) = match &location {
+            Some((path, depth)) => (path.clone(), depth + 1),
+            None => (Utf8PathBuf::new(), 1),
+        };
+        let init_lock = Mutex::new(());
+
+        for submodule in self
+            .cache
+            .submodules(repo, url, filter, location.as_ref())?
+        {
+            let submodule_path = repo.path.join(&submodule.path);
+            let location = (prefix.join(&submodule.path), depth);
+            if submodule_path.is_clone_target()? {
+                println!(
+                    "git-cache: cloning {} into {submodule_path}...",
+                    submodule.url
+                );
+                self.cache
+                    .clone_submodule(repo, &submodule, &options, location, scheduler, &init_lock)?;
+            } else {
+                let mut submodule_entry = ManifestEntry::new(submodule.url.clone());
+                submodule_entry.commit = Some(submodule.commit.clone());
+                submodule_entry.update = update;
+                self.update_entry(&submodule_entry, &submodule_path, scheduler)?;
+
+                let submodule_repo = GitRepo {
+                    path: submodule_path,
+                    safe_directory: false,
+                };
+                self.update_submodules(
+                    &submodule_repo,
+                    &submodule.url,
+                    filter,
+                    Some(location),
+                    update,
+                    scheduler,
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+mod common;
+
+use common::Env;
+
+#[test]
+fn export_import_round_trip() {
+    let env = Env::new();
+    let commits = env.create_upstream("repo", &["a", "b"]);
+    let upstream = env.upstream("repo");
+    let url = upstream.to_str().unwrap();
+    let missing = env.upstream("missing");
+    let bundles = env.path("bundles");
+
+    env.run_git_cache(&["prefetch", url]);
+
+    // uncached repositories are reported, but don't stop the export
+    let output = env.git_cache(&[
+        "export",
+        missing.to_str().unwrap(),
+        url,
+        "--bundle-dir",
+        bundles.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("git-cache:   {}\n", missing.display())));
+    let manifest = std::fs::read_to_string(bundles.join("manifest.txt")).unwrap();
+    assert!(manifest.contains(&format!(".bundle {url}\n")));
+    assert!(!manifest.contains(missing.to_str().unwrap()));
+
+    std::fs::remove_dir_all(env.cache()).unwrap();
+    env.run_git_cache(&["import", bundles.to_str().unwrap()]);
+
+    let mirror = env.mirror("repo");
+    assert_eq!(env.run_git(&mirror, &["config", "remote.origin.url"]), url);
+    assert_eq!(env.run_git(&mirror, &["rev-parse", "main"]), commits[1]);
+
+    // the imported mirror can be cloned from and updated
+    let commit = env.advance_upstream("repo", "c");
+    env.run_git_cache(&["clone", "-U", url, "clone"]);
+    assert_eq!(
+        env.run_git(&env.path("work/clone"), &["rev-parse", "HEAD"]),
+        commit
+    );
+}
+
+#[test]
+fn import_rejects_bundles_outside_bundle_dir() {
+    let env = Env::new();
+    let bundles = env.path("bundles");
+    std::fs::create_dir_all(&bundles).unwrap();
+    std::fs::write(
+        bundles.join("manifest.txt"),
+        format!("../outside.bundle {}\n", env.upstream("repo").display()),
+    )
+    .unwrap();
+
+    let output = env.git_cache(&["import", bundles.to_str().unwrap()]);
+    assert!(!output.status.success());
+    assert!(!env.mirror("repo").exists());
+}
@@ -0,0 +1,503 @@
+mod common;
+
+use common::Env;
+
+/// `super` has `lib/sub` (relative URL), which has `nested` (absolute path).
+/// `super` records the first commit of `sub`, which has been advanced since.
+fn fixture() -> (Env, Fixture) {
+    let env = Env::new();
+    let nested = env.create_upstream("nested", &["a"]);
+    let nested_path = env.upstream("nested");
+    let sub = env.create_superproject(
+        "sub",
+        &[(
+            "nested",
+            "nested",
+            nested_path.to_str().unwrap(),
+            &nested[0],
+        )],
+    );
+    env.advance_upstream("sub", "b");
+    let superproject = env.create_superproject("super", &[("lib/sub", "sub", "../sub.git", &sub)]);
+
+    (
+        env,
+        Fixture {
+            superproject,
+            sub,
+            nested: nested[0].clone(),
+        },
+    )
+}
+
+struct Fixture {
+    superproject: String,
+    sub: String,
+    nested: String,
+}
+
+#[test]
+fn clone_recursive() {
+    let (env, fixture) = fixture();
+    let upstream = env.upstream("super");
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+        "super",
+    ]);
+
+    for name in ["super", "sub", "nested"] {
+        let mirror = env.mirror(name);
+        assert!(mirror.is_dir(), "{mirror:?} missing");
+        assert_eq!(
+            env.run_git(&mirror, &["rev-parse", "--is-bare-repository"]),
+            "true"
+        );
+    }
+
+    let work = env.path("work/super");
+    let sub = work.join("lib/sub");
+    let nested = sub.join("nested");
+
+    let origin = |path| env.run_git(path, &["remote", "get-url", "origin"]);
+    assert_eq!(origin(&work), upstream.to_str().unwrap());
+    assert_eq!(origin(&sub), env.upstream("sub").to_str().unwrap());
+    assert_eq!(origin(&nested), env.upstream("nested").to_str().unwrap());
+
+    let head = |path| env.run_git(path, &["rev-parse", "HEAD"]);
+    assert_eq!(head(&work), fixture.superproject);
+    assert_eq!(head(&sub), fixture.sub);
+    assert_eq!(head(&nested), fixture.nested);
+
+    // the superproject knows its submodules
+    let status = env.run_git(&work, &["submodule", "status", "--recursive"]);
+    assert!(!status.contains('-'), "uninitialized submodules:\n{status}");
+    assert!(
+        !status.contains('+'),
+        "submodules not at recorded commit:\n{status}"
+    );
+}
+
+#[test]
+fn clone_updates_cache_for_missing_commit() {
+    let (env, _) = fixture();
+    let upstream = env.upstream("nested");
+
+    env.run_git_cache(&["clone", upstream.to_str().unwrap(), "first"]);
+    let commit = env.advance_upstream("nested", "c");
+
+    env.run_git_cache(&[
+        "clone",
+        "--commit",
+        &commit,
+        upstream.to_str().unwrap(),
+        "second",
+    ]);
+
+    let head = env.run_git(&env.path("work/second"), &["rev-parse", "HEAD"]);
+    assert_eq!(head, commit);
+}
+
+#[test]
+fn clone_of_unknown_commit_fails() {
+    let (env, _) = fixture();
+    let upstream = env.upstream("nested");
+    env.run_git_cache(&["prefetch", upstream.to_str().unwrap()]);
+
+    let output = env.git_cache(&[
+        "clone",
+        "--commit",
+        "0123456789012345678901234567890123456789",
+        upstream.to_str().unwrap(),
+        "clone",
+    ]);
+
+    assert!(!output.status.success());
+    assert!(!env.path("work/clone").exists());
+}
+
+#[test]
+fn file_urls_share_mirror_with_paths() {
+    let (env, _) = fixture();
+    let upstream = env.upstream("nested");
+
+    env.run_git_cache(&[
+        "clone",
+        &format!("file://{}", upstream.display()),
+        "via-url",
+    ]);
+    env.run_git_cache(&["clone", upstream.to_str().unwrap(), "via-path"]);
+
+    assert!(env.mirror("nested").is_dir());
+    let local = env.cache().join("local");
+    let mirrors = walk_mirrors(&local);
+    assert_eq!(mirrors, [env.mirror("nested")]);
+}
+
+fn walk_mirrors(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut mirrors = Vec::new();
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if !path.is_dir() {
+            continue;
+        }
+        if path.extension().is_some_and(|ext| ext == "git") {
+            mirrors.push(path);
+        } else {
+            mirrors.extend(walk_mirrors(&path));
+        }
+    }
+    mirrors
+}
+
+#[test]
+fn clone_remote_submodules() {
+    let env = Env::new();
+    let first = env.create_upstream("main-sub", &["a"]);
+    let main_tip = env.advance_upstream("main-sub", "b");
+    env.create_upstream("dev-sub", &["a"]);
+
+    // `dev-sub` has a `dev` branch that is ahead of `main`
+    let worktree = env.path("dev-sub.worktree");
+    env.run_git(&worktree, &["checkout", "-q", "-b", "dev"]);
+    std::fs::write(worktree.join("c"), "c").unwrap();
+    env.run_git(&worktree, &["add", "c"]);
+    env.run_git(&worktree, &["commit", "-q", "-m", "c"]);
+    let dev_tip = env.run_git(&worktree, &["rev-parse", "HEAD"]);
+    let upstream = env.upstream("dev-sub");
+    env.run_git(
+        &worktree,
+        &["push", "-q", upstream.to_str().unwrap(), "dev"],
+    );
+    let dev_first = env.run_git(&worktree, &["rev-parse", "main"]);
+
+    let main_sub = env.upstream("main-sub");
+    let dev_sub = env.upstream("dev-sub");
+    env.create_superproject(
+        "super",
+        &[
+            ("same", "main-sub", main_sub.to_str().unwrap(), &first[0]),
+            ("dev", "dev-sub", dev_sub.to_str().unwrap(), &dev_first),
+        ],
+    );
+
+    // `same` follows the superproject's branch, `dev` follows `dev`
+    let worktree = env.path("super.worktree");
+    env.run_git(
+        &worktree,
+        &["config", "-f", ".gitmodules", "submodule.same.branch", "."],
+    );
+    env.run_git(
+        &worktree,
+        &["config", "-f", ".gitmodules", "submodule.dev.branch", "dev"],
+    );
+    env.run_git(&worktree, &["commit", "-q", "-a", "-m", "track branches"]);
+    let upstream = env.upstream("super");
+    env.run_git(
+        &worktree,
+        &["push", "-q", upstream.to_str().unwrap(), "main"],
+    );
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        "--remote-submodules",
+        upstream.to_str().unwrap(),
+        "remote",
+    ]);
+    let work = env.path("work/remote");
+    let head = |path: &str| env.run_git(&work.join(path), &["rev-parse", "HEAD"]);
+    assert_eq!(head("same"), main_tip);
+    assert_eq!(head("dev"), dev_tip);
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+        "recorded",
+    ]);
+    let work = env.path("work/recorded");
+    let head = |path: &str| env.run_git(&work.join(path), &["rev-parse", "HEAD"]);
+    assert_eq!(head("same"), first[0]);
+    assert_eq!(head("dev"), dev_first);
+}
+
+#[test]
+fn clone_skips_inactive_submodules() {
+    let env = Env::new();
+    let commit = env.create_upstream("sub", &["a"]);
+    let sub = env.upstream("sub");
+    let sub = sub.to_str().unwrap();
+    env.create_superproject(
+        "super",
+        &[
+            ("a", "sub", sub, &commit[0]),
+            ("b", "sub", sub, &commit[0]),
+            ("c", "sub", sub, &commit[0]),
+        ],
+    );
+
+    let worktree = env.path("super.worktree");
+    env.run_git(
+        &worktree,
+        &["config", "-f", ".gitmodules", "submodule.b.update", "none"],
+    );
+    env.run_git(&worktree, &["commit", "-q", "-a", "-m", "b is optional"]);
+    let upstream = env.upstream("super");
+    env.run_git(
+        &worktree,
+        &["push", "-q", upstream.to_str().unwrap(), "main"],
+    );
+
+    let cloned = |target: &str| {
+        let work = env.path("work").join(target);
+        ["a", "b", "c"]
+            .into_iter()
+            .filter(|path| work.join(path).join(".git").exists())
+            .collect::<Vec<_>>()
+    };
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+        "default",
+    ]);
+    assert_eq!(cloned("default"), ["a", "c"]);
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        "--config",
+        "submodule.active=:!c",
+        upstream.to_str().unwrap(),
+        "active",
+    ]);
+    assert_eq!(cloned("active"), ["a"]);
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        "--config",
+        "submodule.active=.",
+        "--config",
+        "submodule.a.active=false",
+        upstream.to_str().unwrap(),
+        "name-active",
+    ]);
+    assert_eq!(cloned("name-active"), ["c"]);
+}
+
+#[test]
+fn clone_filters_nested_submodules() {
+    let env = Env::new();
+    let data = env.create_upstream("data", &["a"]);
+    let path = env.upstream("data");
+    let lib = env.create_superproject(
+        "lib",
+        &[("testdata", "data", path.to_str().unwrap(), &data[0])],
+    );
+    let path = env.upstream("lib");
+    env.create_superproject("super", &[("lib", "lib", path.to_str().unwrap(), &lib)]);
+    let upstream = env.upstream("super");
+
+    let cloned = |target: &str| {
+        let work = env.path("work").join(target);
+        ["lib", "lib/testdata"]
+            .into_iter()
+            .filter(|path| work.join(path).join(".git").exists())
+            .collect::<Vec<_>>()
+    };
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+        "all",
+    ]);
+    assert_eq!(cloned("all"), ["lib", "lib/testdata"]);
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        "--exclude-submodule",
+        "*/testdata",
+        upstream.to_str().unwrap(),
+        "excluded",
+    ]);
+    assert_eq!(cloned("excluded"), ["lib"]);
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules",
+        "--submodule-depth",
+        "1",
+        upstream.to_str().unwrap(),
+        "depth",
+    ]);
+    assert_eq!(cloned("depth"), ["lib"]);
+
+    env.run_git_cache(&[
+        "clone",
+        "--recurse-submodules=l*",
+        upstream.to_str().unwrap(),
+        "glob",
+    ]);
+    assert_eq!(cloned("glob"), ["lib", "lib/testdata"]);
+}
+
+#[test]
+fn clone_retries_transient_errors_only() {
+    let env = Env::new();
+
+    // nothing listens on port 1, so the connection gets refused
+    let output = env.git_cache(&[
+        "--retries",
+        "2",
+        "--retry-backoff",
+        "0",
+        "clone",
+        "http://127.0.0.1:1/repo.git",
+    ]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("retrying").count(), 2, "{stdout}");
+
+    let missing = env.upstream("missing");
+    let output = env.git_cache(&["--retries", "2", "clone", missing.to_str().unwrap()]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(!stdout.contains("retrying"), "{stdout}");
+    assert!(!env.mirror("missing").exists());
+}
+
+#[test]
+fn clone_falls_back_to_alternates() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+
+    // nothing listens on port 1, fetch from `up/` instead
+    let url = "http://127.0.0.1:1/repo.git";
+    env.run_git(
+        &env.path(""),
+        &[
+            "config",
+            "--global",
+            "gitcache.http://127.0.0.1:1/.alternate",
+            &format!("{}/", env.path("up").display()),
+        ],
+    );
+
+    env.run_git_cache(&["clone", url, "clone"]);
+    let clone = env.path("work/clone");
+    assert!(clone.join("a").exists());
+    assert_eq!(env.run_git(&clone, &["remote", "get-url", "origin"]), url);
+
+    env.advance_upstream("repo", "b");
+    env.run_git_cache(&["clone", "-U", url, "updated"]);
+    let updated = env.path("work/updated");
+    assert!(updated.join("b").exists());
+    assert_eq!(env.run_git(&updated, &["remote", "get-url", "origin"]), url);
+    let mirror = env.cache().join("127.0.0.1/repo.git");
+    assert_eq!(env.run_git(&mirror, &["remote", "get-url", "origin"]), url);
+}
+
+#[test]
+fn clone_with_readonly_tier() {
+    let env = Env::new();
+    let commits = env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+    let url = upstream.to_str().unwrap();
+    let tier = env.path("tier");
+
+    let output = env.git_cache_with_cache_dirs(&[&tier], &["prefetch", url]);
+    assert!(output.status.success());
+
+    let output = env.git_cache_with_cache_dirs(&[&env.cache(), &tier], &["clone", url, "clone"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("as reference"), "{stdout}");
+
+    // the writable mirror borrows the objects of the tier
+    let mirror = env.mirror("repo");
+    let alternates = std::fs::read_to_string(mirror.join("objects/info/alternates")).unwrap();
+    assert!(
+        alternates.starts_with(tier.to_str().unwrap()),
+        "{alternates}"
+    );
+    env.run_git(&mirror, &["fsck", "--no-progress"]);
+    assert_eq!(env.run_git(&mirror, &["rev-parse", "main"]), commits[0]);
+
+    std::fs::remove_dir_all(&tier).unwrap();
+    let output = env.git_cache(&["doctor"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("borrows objects from"), "{stdout}");
+}
+
+#[test]
+fn clone_dry_run() {
+    let (env, fixture) = fixture();
+    let upstream = env.upstream("super");
+    let args = [
+        "clone",
+        "--dry-run",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+        "super",
+    ];
+
+    let output = env.git_cache(&args);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "git-cache: would mirror {} into {}",
+        upstream.display(),
+        env.mirror("super").display()
+    )));
+    assert!(stdout.contains(&format!(
+        "git-cache: would look for submodules of {} once it is cached",
+        upstream.display()
+    )));
+    assert!(!env.mirror("super").exists());
+    assert!(!env.path("work/super").exists());
+
+    env.run_git_cache(&[
+        "prefetch",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+    ]);
+    let output = env.git_cache(&args);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!(
+        "git-cache: would use cached {}",
+        env.mirror("sub").display()
+    )));
+    assert!(stdout.contains(&format!(
+        "git-cache: would check out {} in super/lib/sub",
+        fixture.sub
+    )));
+    assert!(stdout.contains(&format!(
+        "git-cache: would check out {} in super/lib/sub/nested",
+        fixture.nested
+    )));
+    assert!(!env.path("work/super").exists());
+
+    // submodules the clone would configure as inactive are left out
+    let output = env.git_cache(&[
+        "clone",
+        "--dry-run",
+        "--recurse-submodules",
+        "--config",
+        "submodule.active=:!lib/sub",
+        upstream.to_str().unwrap(),
+        "super",
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("git-cache: would skip submodule `lib/sub` (inactive)"));
+    assert!(!stdout.contains("super/lib/sub"));
+}
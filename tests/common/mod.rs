@@ -0,0 +1,234 @@
+//! Helpers for running `git-cache` against local fixture repositories.
+
+#![allow(dead_code)]
+
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use tempfile::TempDir;
+
+/// A temporary directory with upstream repositories below `up/`, a cache
+/// in `cache/` and room for clones in `work/`.
+pub struct Env {
+    dir: TempDir,
+}
+
+impl Env {
+    pub fn new() -> Self {
+        let env = Self {
+            dir: tempfile::tempdir().unwrap(),
+        };
+        std::fs::create_dir_all(env.path("up")).unwrap();
+        std::fs::create_dir_all(env.path("work")).unwrap();
+        env
+    }
+
+    pub fn path(&self, path: &str) -> PathBuf {
+        self.dir.path().join(path)
+    }
+
+    pub fn cache(&self) -> PathBuf {
+        self.path("cache")
+    }
+
+    /// Returns the cache path of the mirror of the upstream `name`.
+    pub fn mirror(&self, name: &str) -> PathBuf {
+        let upstream = self.upstream(name);
+        self.cache()
+            .join("local")
+            .join(upstream.strip_prefix("/").unwrap())
+    }
+
+    /// Returns the path of the bare upstream repository `name`.
+    pub fn upstream(&self, name: &str) -> PathBuf {
+        self.path("up").join(format!("{name}.git"))
+    }
+
+    /// Sets up `git` with a predictable environment.
+    pub fn git(&self, dir: &Path) -> Command {
+        self.command("git", dir)
+    }
+
+    fn command(&self, program: &str, dir: &Path) -> Command {
+        let mut command = Command::new(program);
+        command
+            .current_dir(dir)
+            .env("HOME", self.dir.path())
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_AUTHOR_NAME", "test")
+            .env("GIT_AUTHOR_EMAIL", "test@example.com")
+            .env("GIT_COMMITTER_NAME", "test")
+            .env("GIT_COMMITTER_EMAIL", "test@example.com")
+            // local submodules are refused by default since git 2.38.1
+            .env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "protocol.file.allow")
+            .env("GIT_CONFIG_VALUE_0", "always");
+        command
+    }
+
+    /// Runs `git` in `dir`, returning its trimmed stdout.
+    pub fn run_git(&self, dir: &Path, args: &[&str]) -> String {
+        let output = self.git(dir).args(args).output().unwrap();
+        assert_success("git", args, &output);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Runs `git-cache --cache-local-repos` in `work/`.
+    pub fn git_cache(&self, args: &[&str]) -> Output {
+        self.git_cache_command(args).output().unwrap()
+    }
+
+    /// Like [`Env::git_cache()`], with `stdin` as standard input.
+    pub fn git_cache_with_stdin(&self, args: &[&str], stdin: &str) -> Output {
+        let mut child = self
+            .git_cache_command(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stdin.as_bytes())
+            .unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    /// Like [`Env::git_cache()`], with `cache_dirs` (e.g., including
+    /// read-only tiers) instead of `cache/`.
+    pub fn git_cache_with_cache_dirs(&self, cache_dirs: &[&Path], args: &[&str]) -> Output {
+        let cache_dirs = std::env::join_paths(cache_dirs).unwrap();
+        self.git_cache_command_in(&cache_dirs, args)
+            .output()
+            .unwrap()
+    }
+
+    /// Returns the command run by [`Env::git_cache()`], to be adjusted.
+    pub fn git_cache_command(&self, args: &[&str]) -> Command {
+        self.git_cache_command_in(self.cache().as_os_str(), args)
+    }
+
+    fn git_cache_command_in(&self, cache_dirs: &OsStr, args: &[&str]) -> Command {
+        let mut command = self.command(env!("CARGO_BIN_EXE_git-cache"), &self.path("work"));
+        command
+            .arg("--cache-local-repos")
+            .arg("--cache-dir")
+            .arg(cache_dirs)
+            .args(args);
+        command
+    }
+
+    /// Like [`Env::git_cache()`], but asserts success.
+    pub fn run_git_cache(&self, args: &[&str]) {
+        let output = self.git_cache(args);
+        assert_success("git-cache", args, &output);
+    }
+
+    /// Creates the bare upstream repository `name` with one commit per entry
+    /// of `files`, returning the commit hashes.
+    pub fn create_upstream(&self, name: &str, files: &[&str]) -> Vec<String> {
+        let worktree = self.path(&format!("{name}.worktree"));
+        self.run_git(
+            self.dir.path(),
+            &["init", "-q", "-b", "main", worktree.to_str().unwrap()],
+        );
+
+        let commits = files
+            .iter()
+            .map(|file| self.commit_file(&worktree, file))
+            .collect();
+
+        self.run_git(
+            self.dir.path(),
+            &[
+                "clone",
+                "-q",
+                "--bare",
+                worktree.to_str().unwrap(),
+                self.upstream(name).to_str().unwrap(),
+            ],
+        );
+        commits
+    }
+
+    /// Adds a commit to the upstream `name`, returning its hash.
+    pub fn advance_upstream(&self, name: &str, file: &str) -> String {
+        let worktree = self.path(&format!("{name}.worktree"));
+        let commit = self.commit_file(&worktree, file);
+        let upstream = self.upstream(name);
+        self.run_git(
+            &worktree,
+            &["push", "-q", upstream.to_str().unwrap(), "main"],
+        );
+        commit
+    }
+
+    /// Creates the upstream `name` with submodules given as
+    /// `(path, upstream, url, commit)`, where `url` is what ends up in
+    /// `.gitmodules` (e.g., a relative URL). Returns the commit hash.
+    pub fn create_superproject(
+        &self,
+        name: &str,
+        submodules: &[(&str, &str, &str, &str)],
+    ) -> String {
+        let worktree = self.path(&format!("{name}.worktree"));
+        self.run_git(
+            self.dir.path(),
+            &["init", "-q", "-b", "main", worktree.to_str().unwrap()],
+        );
+        self.commit_file(&worktree, "README");
+
+        for (path, upstream, url, commit) in submodules {
+            let upstream = self.upstream(upstream);
+            self.run_git(
+                &worktree,
+                &["submodule", "add", "-q", upstream.to_str().unwrap(), path],
+            );
+            self.run_git(&worktree.join(path), &["checkout", "-q", commit]);
+            self.run_git(&worktree, &["add", path]);
+            self.run_git(
+                &worktree,
+                &[
+                    "config",
+                    "-f",
+                    ".gitmodules",
+                    &format!("submodule.{path}.url"),
+                    url,
+                ],
+            );
+        }
+        self.run_git(&worktree, &["commit", "-q", "-a", "-m", "add submodules"]);
+
+        self.run_git(
+            self.dir.path(),
+            &[
+                "clone",
+                "-q",
+                "--bare",
+                worktree.to_str().unwrap(),
+                self.upstream(name).to_str().unwrap(),
+            ],
+        );
+        self.run_git(&worktree, &["rev-parse", "HEAD"])
+    }
+
+    fn commit_file(&self, worktree: &Path, file: &str) -> String {
+        std::fs::write(worktree.join(file), file).unwrap();
+        self.run_git(worktree, &["add", file]);
+        self.run_git(worktree, &["commit", "-q", "-m", file]);
+        self.run_git(worktree, &["rev-parse", "HEAD"])
+    }
+}
+
+fn assert_success(program: &str, args: &[&str], output: &Output) {
+    assert!(
+        output.status.success(),
+        "{program} {args:?} failed:\n{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
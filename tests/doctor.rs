@@ -0,0 +1,29 @@
+mod common;
+
+use common::Env;
+
+#[test]
+fn doctor_reports_foreign_mirrors() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+    env.run_git_cache(&["prefetch", upstream.to_str().unwrap()]);
+    env.run_git_cache(&["doctor"]);
+
+    // makes git treat the mirror as owned by another user
+    let output = env
+        .git_cache_command(&["doctor"])
+        .env("GIT_TEST_ASSUME_DIFFERENT_OWNER", "1")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        stdout.contains(&format!(
+            "problem: {}: owned by another user",
+            env.mirror("repo").display()
+        )),
+        "{stdout}"
+    );
+    assert!(!stdout.contains("interrupted clone"), "{stdout}");
+}
@@ -0,0 +1,83 @@
+mod common;
+
+use common::Env;
+
+#[test]
+fn lfs_objects_are_cached() {
+    let env = Env::new();
+    let lfs = env.git(&env.path("work")).args(["lfs", "version"]).output();
+    if !lfs.is_ok_and(|output| output.status.success()) {
+        eprintln!("skipping, git-lfs is not installed");
+        return;
+    }
+
+    let base = env.path("");
+    env.run_git(&base, &["lfs", "install", "--skip-repo"]);
+
+    let upstream = env.upstream("lfs");
+    let url = format!("file://{}", upstream.display());
+    env.run_git(
+        &base,
+        &[
+            "init",
+            "-q",
+            "--bare",
+            "-b",
+            "main",
+            upstream.to_str().unwrap(),
+        ],
+    );
+
+    let worktree = env.path("lfs.worktree");
+    env.run_git(
+        &base,
+        &["init", "-q", "-b", "main", worktree.to_str().unwrap()],
+    );
+    env.run_git(&worktree, &["lfs", "track", "*.bin"]);
+    std::fs::write(worktree.join("data.bin"), "large file contents").unwrap();
+    env.run_git(&worktree, &["add", ".gitattributes", "data.bin"]);
+    env.run_git(&worktree, &["commit", "-q", "-m", "add data"]);
+    env.run_git(&worktree, &["push", "-q", &url, "main"]);
+
+    env.run_git_cache(&["clone", &url, "first"]);
+
+    let mirror = env.mirror("lfs");
+    assert!(mirror.join("lfs/objects").is_dir());
+
+    let first = env.path("work/first");
+    assert_eq!(
+        std::fs::read_to_string(first.join("data.bin")).unwrap(),
+        "large file contents"
+    );
+    assert_eq!(env.run_git(&first, &["remote", "get-url", "origin"]), url);
+
+    // the mirror is only used for the initial pull
+    let lfsurl = env
+        .git(&first)
+        .args(["config", "remote.origin.lfsurl"])
+        .output()
+        .unwrap();
+    assert!(!lfsurl.status.success());
+
+    // the clone has its own LFS storage
+    assert!(first.join(".git/lfs/objects").is_dir());
+
+    // further clones get the LFS objects from the cache
+    std::fs::remove_dir_all(upstream.join("lfs")).unwrap();
+    env.run_git_cache(&["clone", &url, "second"]);
+    assert_eq!(
+        std::fs::read_to_string(env.path("work/second/data.bin")).unwrap(),
+        "large file contents"
+    );
+
+    // later pulls get new LFS objects from upstream
+    std::fs::write(worktree.join("more.bin"), "more large file contents").unwrap();
+    env.run_git(&worktree, &["add", "more.bin"]);
+    env.run_git(&worktree, &["commit", "-q", "-m", "add more data"]);
+    env.run_git(&worktree, &["push", "-q", &url, "main"]);
+    env.run_git(&first, &["pull", "-q"]);
+    assert_eq!(
+        std::fs::read_to_string(first.join("more.bin")).unwrap(),
+        "more large file contents"
+    );
+}
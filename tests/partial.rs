@@ -0,0 +1,198 @@
+mod common;
+
+use std::path::Path;
+
+use common::Env;
+
+/// Commits `contents` as `data` in the worktree of the upstream `name`.
+fn commit_data(env: &Env, name: &str, contents: &str) -> String {
+    let worktree = env.path(&format!("{name}.worktree"));
+    std::fs::write(worktree.join("data"), contents).unwrap();
+    env.run_git(&worktree, &["commit", "-q", "-a", "-m", contents]);
+    let upstream = env.upstream(name);
+    env.run_git(
+        &worktree,
+        &["push", "-q", upstream.to_str().unwrap(), "main"],
+    );
+    env.run_git(&worktree, &["rev-parse", "HEAD"])
+}
+
+/// Returns the number of objects referenced, but missing in `repo`.
+fn missing_objects(env: &Env, repo: &Path) -> usize {
+    env.run_git(repo, &["rev-list", "--objects", "--all", "--missing=print"])
+        .lines()
+        .filter(|line| line.starts_with('?'))
+        .count()
+}
+
+fn upstream(env: &Env) -> (String, Vec<String>) {
+    env.create_upstream("repo", &["data"]);
+    let commits = vec![
+        commit_data(env, "repo", "v1"),
+        commit_data(env, "repo", "v2"),
+    ];
+    let upstream = env.upstream("repo");
+    env.run_git(&upstream, &["config", "uploadpack.allowFilter", "true"]);
+    env.run_git(
+        &upstream,
+        &["config", "uploadpack.allowAnySHA1InWant", "true"],
+    );
+    (format!("file://{}", upstream.display()), commits)
+}
+
+#[test]
+fn clone_through_partial_mirror() {
+    let env = Env::new();
+    let (url, commits) = upstream(&env);
+
+    env.run_git_cache(&["clone", "--filter=blob:none", &url, "clone"]);
+
+    let mirror = env.mirror("repo");
+    assert_eq!(
+        env.run_git(&mirror, &["config", "remote.origin.partialclonefilter"]),
+        "blob:none"
+    );
+
+    let clone = env.path("work/clone");
+    assert_eq!(std::fs::read_to_string(clone.join("data")).unwrap(), "v2");
+    assert_eq!(env.run_git(&clone, &["remote", "get-url", "origin"]), url);
+    assert_eq!(
+        env.run_git(&clone, &["config", "remote.gitcache.promisor"]),
+        "true"
+    );
+
+    // only the blobs of HEAD have been fetched
+    let missing = missing_objects(&env, &mirror);
+    assert_eq!(missing, 2);
+
+    // older blobs get fetched through the mirror
+    env.run_git(&clone, &["checkout", "-q", &commits[0]]);
+    assert_eq!(std::fs::read_to_string(clone.join("data")).unwrap(), "v1");
+    assert_eq!(missing_objects(&env, &mirror), missing - 1);
+}
+
+#[test]
+fn partial_mirror_keeps_filter_on_update() {
+    let env = Env::new();
+    let (url, _) = upstream(&env);
+
+    env.run_git_cache(&["prefetch", "--filter=blob:none", &url]);
+    let mirror = env.mirror("repo");
+    let missing = missing_objects(&env, &mirror);
+
+    commit_data(&env, "repo", "v3");
+    env.run_git_cache(&["prefetch", "--update", &url]);
+
+    assert_eq!(missing_objects(&env, &mirror), missing + 1);
+}
+
+#[test]
+fn filter_from_git_config() {
+    let env = Env::new();
+    let (url, _) = upstream(&env);
+
+    let prefix = format!("file://{}/", env.path("up").display());
+    env.run_git(
+        &env.path(""),
+        &[
+            "config",
+            "--global",
+            &format!("gitcache.{prefix}.filter"),
+            "blob:none",
+        ],
+    );
+
+    env.run_git_cache(&["clone", &url, "clone"]);
+
+    let mirror = env.mirror("repo");
+    assert_eq!(
+        env.run_git(&mirror, &["config", "remote.origin.partialclonefilter"]),
+        "blob:none"
+    );
+    assert_eq!(
+        std::fs::read_to_string(env.path("work/clone/data")).unwrap(),
+        "v2"
+    );
+}
+
+#[test]
+fn fetch_through_partial_mirror() {
+    let env = Env::new();
+    let (url, _) = upstream(&env);
+
+    env.run_git_cache(&["clone", "--filter=blob:none", &url, "clone"]);
+    let commit = commit_data(&env, "repo", "v3");
+
+    let clone = env.path("work/clone");
+    env.run_git_cache(&["fetch", clone.to_str().unwrap()]);
+    assert_eq!(env.run_git(&clone, &["rev-parse", "origin/main"]), commit);
+
+    env.run_git(&clone, &["merge", "-q", "--ff-only", "origin/main"]);
+    assert_eq!(std::fs::read_to_string(clone.join("data")).unwrap(), "v3");
+}
+
+#[test]
+fn submodule_mirrors_filtered_on_request() {
+    let env = Env::new();
+    let (url, commits) = upstream(&env);
+    env.create_superproject("super", &[("repo", "repo", &url, &commits[1])]);
+    let upstream = env.upstream("super");
+    env.run_git(&upstream, &["config", "uploadpack.allowFilter", "true"]);
+    let super_url = format!("file://{}", upstream.display());
+    let is_partial = |name| {
+        env.git(&env.mirror(name))
+            .args(["config", "remote.origin.promisor"])
+            .output()
+            .unwrap()
+            .status
+            .success()
+    };
+
+    // like with git, `--filter` doesn't apply to submodules by default
+    let args = [
+        "clone",
+        "--filter=blob:none",
+        "--recurse-submodules",
+        &super_url,
+    ];
+    env.run_git_cache(&[&args[..], &["first"]].concat());
+    assert!(is_partial("super"));
+    assert!(!is_partial("repo"));
+    assert_eq!(
+        std::fs::read_to_string(env.path("work/first/repo/data")).unwrap(),
+        "v2"
+    );
+
+    std::fs::remove_dir_all(env.cache()).unwrap();
+    env.run_git_cache(&[&args[..], &["--also-filter-submodules", "second"]].concat());
+    assert!(is_partial("super"));
+    assert!(is_partial("repo"));
+    assert_eq!(
+        std::fs::read_to_string(env.path("work/second/repo/data")).unwrap(),
+        "v2"
+    );
+}
+
+#[test]
+fn shared_partial_mirror_with_quote_in_path() {
+    let env = Env::new();
+    let (url, commits) = upstream(&env);
+    let cache = env.path("git's cache");
+
+    let output = env.git_cache_with_cache_dirs(
+        &[&cache],
+        &[
+            "--shared-cache",
+            "clone",
+            "--filter=blob:none",
+            &url,
+            "clone",
+        ],
+    );
+    assert!(output.status.success());
+
+    // fetching missing objects runs `upload-pack` in the mirror
+    let clone = env.path("work/clone");
+    env.run_git(&clone, &["checkout", "-q", &commits[0]]);
+    assert_eq!(std::fs::read_to_string(clone.join("data")).unwrap(), "v1");
+}
@@ -0,0 +1,67 @@
+mod common;
+
+use common::Env;
+
+#[test]
+fn path_of_mirrors() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+    let url = upstream.to_str().unwrap();
+    let mirror = env.mirror("repo");
+
+    let output = env.git_cache(&["path", url]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", mirror.display())
+    );
+
+    let output = env.git_cache(&["which", "--check", "--status", url]);
+    assert!(!output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\tnot cached\n", mirror.display())
+    );
+    assert!(!mirror.exists());
+
+    env.run_git_cache(&["prefetch", url]);
+    let output = env.git_cache(&["path", "--check", "--status", url]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\tcached\n", mirror.display())
+    );
+}
+
+#[test]
+fn path_of_mirrors_in_readonly_tiers() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+    let url = upstream.to_str().unwrap();
+    let tier = env.path("tier");
+    let tiers = [env.cache(), tier.clone()];
+    let tiers = tiers.iter().map(|dir| dir.as_path()).collect::<Vec<_>>();
+
+    let output = env.git_cache_with_cache_dirs(&[&tier], &["prefetch", url]);
+    assert!(output.status.success());
+    let tier_mirror = env.mirror("repo");
+    let tier_mirror = tier.join(tier_mirror.strip_prefix(env.cache()).unwrap());
+
+    let output = env.git_cache_with_cache_dirs(&tiers, &["path", "--check", "--status", url]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\tcached\n", tier_mirror.display())
+    );
+
+    // the writable cache comes first
+    let output = env.git_cache_with_cache_dirs(&tiers, &["prefetch", url]);
+    assert!(output.status.success());
+    let output = env.git_cache_with_cache_dirs(&tiers, &["path", url]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!("{}\n", env.mirror("repo").display())
+    );
+}
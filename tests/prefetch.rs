@@ -0,0 +1,391 @@
+mod common;
+
+use common::Env;
+
+#[test]
+fn prefetch_recursive() {
+    let env = Env::new();
+    let nested = env.create_upstream("nested", &["a"]);
+    let sub = env.create_superproject("sub", &[("nested", "nested", "../nested.git", &nested[0])]);
+    env.create_superproject("super", &[("sub", "sub", "../sub.git", &sub)]);
+
+    let upstream = env.upstream("super");
+    env.run_git_cache(&[
+        "prefetch",
+        "--recurse-submodules",
+        upstream.to_str().unwrap(),
+    ]);
+
+    for name in ["super", "sub", "nested"] {
+        let mirror = env.mirror(name);
+        assert!(mirror.is_dir(), "{mirror:?} missing");
+        assert_eq!(
+            env.run_git(&mirror, &["config", "remote.origin.url"]),
+            env.upstream(name).to_str().unwrap()
+        );
+    }
+}
+
+#[test]
+fn prefetch_shared_submodules() {
+    let env = Env::new();
+    let nested = env.create_upstream("nested", &["a"]);
+    let a = env.create_superproject("a", &[("nested", "nested", "../nested.git", &nested[0])]);
+    let b = env.create_superproject("b", &[("nested", "nested", "../nested.git", &nested[0])]);
+    env.create_superproject(
+        "super",
+        &[("a", "a", "../a.git", &a), ("b", "b", "../b.git", &b)],
+    );
+
+    let output = env.git_cache(&[
+        "prefetch",
+        "--recurse-submodules",
+        "--show-graph",
+        "-j4",
+        env.upstream("super").to_str().unwrap(),
+    ]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let upstream = |name| env.upstream(name).display().to_string();
+    assert_eq!(
+        stdout
+            .matches(&format!("prefetched {}", upstream("nested")))
+            .count(),
+        1
+    );
+    assert!(stdout.contains(&format!(
+        "{}\n├── {}\n│   └── {}\n└── {}\n    └── {}\n",
+        upstream("super"),
+        upstream("a"),
+        upstream("nested"),
+        upstream("b"),
+        upstream("nested"),
+    )));
+}
+
+#[test]
+fn prefetch_update() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+
+    env.run_git_cache(&["prefetch", upstream.to_str().unwrap()]);
+    let commit = env.advance_upstream("repo", "b");
+
+    env.run_git_cache(&["prefetch", upstream.to_str().unwrap()]);
+    let mirror = env.mirror("repo");
+    assert_ne!(env.run_git(&mirror, &["rev-parse", "main"]), commit);
+
+    env.run_git_cache(&["prefetch", "--update", upstream.to_str().unwrap()]);
+    assert_eq!(env.run_git(&mirror, &["rev-parse", "main"]), commit);
+}
+
+#[test]
+fn local_repositories_are_not_cached_by_default() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_git-cache"))
+        .current_dir(env.path("work"))
+        .arg("--cache-dir")
+        .arg(env.cache())
+        .args(["prefetch", upstream.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(!env.cache().join("local").exists());
+}
+
+#[test]
+fn prefetch_from_file() {
+    let env = Env::new();
+    let nested = env.create_upstream("nested", &["a"]);
+    env.create_superproject(
+        "super",
+        &[("nested", "nested", "../nested.git", &nested[0])],
+    );
+    env.create_upstream("repo", &["a"]);
+
+    let list = env.path("repositories.txt");
+    std::fs::write(
+        &list,
+        format!(
+            "# prefetched nightly\n\n{} recurse\n{}  # no submodules\n",
+            env.upstream("super").display(),
+            env.upstream("repo").display()
+        ),
+    )
+    .unwrap();
+    env.run_git_cache(&["prefetch", "--from-file", list.to_str().unwrap()]);
+    for name in ["super", "nested", "repo"] {
+        assert!(env.mirror(name).is_dir(), "{name} missing");
+    }
+
+    // per-line options override the command line
+    let commit = env.advance_upstream("repo", "b");
+    let output = env.git_cache_with_stdin(
+        &["prefetch", "--from-file", "-"],
+        &format!("{} update\n", env.upstream("repo").display()),
+    );
+    assert!(output.status.success());
+    let mirror = env.mirror("repo");
+    assert_eq!(env.run_git(&mirror, &["rev-parse", "main"]), commit);
+}
+
+#[test]
+fn prefetch_nothing() {
+    let env = Env::new();
+
+    // an empty list is not an error
+    let output = env.git_cache_with_stdin(&["prefetch", "--from-file", "-"], "# nothing\n");
+    assert!(output.status.success());
+
+    // neither are lock files without git dependencies
+    let lock = env.path("Cargo.lock");
+    std::fs::write(
+        &lock,
+        "version = 4\n\n[[package]]\nname = \"local\"\nversion = \"0.1.0\"\n",
+    )
+    .unwrap();
+    env.run_git_cache(&["prefetch", "--cargo-lock", lock.to_str().unwrap()]);
+}
+
+#[test]
+fn prefetch_cargo_lock() {
+    let env = Env::new();
+    let commits = env.create_upstream("dep", &["a"]);
+    let url = format!("file://{}", env.upstream("dep").display());
+
+    let lock = |commit: &str| {
+        let path = env.path("Cargo.lock");
+        std::fs::write(
+            &path,
+            format!(
+                "version = 4\n\n\
+                 [[package]]\nname = \"dep\"\nversion = \"0.1.0\"\n\
+                 source = \"git+{url}?branch=main#{commit}\"\n"
+            ),
+        )
+        .unwrap();
+        path
+    };
+
+    let config = env.path("config.toml");
+    env.run_git_cache(&[
+        "prefetch",
+        "--cargo-lock",
+        lock(&commits[0]).to_str().unwrap(),
+        "--cargo-config",
+        config.to_str().unwrap(),
+    ]);
+    let mirror = env.mirror("dep");
+    assert!(mirror.is_dir());
+    let config = std::fs::read_to_string(config).unwrap();
+    assert!(config.contains(&format!(
+        "[source.\"git+{url}?branch=main\"]\ngit = \"{url}\"\nbranch = \"main\"\nreplace-with = \"git-cache-0\"\n"
+    )));
+    assert!(config.contains(&format!(
+        "[source.git-cache-0]\ngit = \"file://{}\"\nbranch = \"main\"\n",
+        mirror.display()
+    )));
+
+    // a newer locked commit updates the mirror
+    let commit = env.advance_upstream("dep", "b");
+    env.run_git_cache(&["prefetch", "--cargo-lock", lock(&commit).to_str().unwrap()]);
+    assert_eq!(env.run_git(&mirror, &["rev-parse", "main"]), commit);
+
+    let output = env.git_cache(&[
+        "prefetch",
+        "--cargo-lock",
+        lock("0123456789012345678901234567890123456789")
+            .to_str()
+            .unwrap(),
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("does not contain commit"));
+
+    // the settings are rewritten, but other files are left alone
+    let config = env.path("config.toml");
+    env.run_git_cache(&[
+        "prefetch",
+        "--cargo-lock",
+        lock(&commit).to_str().unwrap(),
+        "--cargo-config",
+        config.to_str().unwrap(),
+    ]);
+    let other = env.path("other.toml");
+    std::fs::write(
+        &other,
+        "[build]
+jobs = 1
+",
+    )
+    .unwrap();
+    let output = env.git_cache(&[
+        "prefetch",
+        "--cargo-lock",
+        lock(&commit).to_str().unwrap(),
+        "--cargo-config",
+        other.to_str().unwrap(),
+    ]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not overwriting it"));
+    assert_eq!(
+        std::fs::read_to_string(&other).unwrap(),
+        "[build]\njobs = 1\n"
+    );
+
+    // stdout is taken by the status output
+    let output = env.git_cache(&[
+        "prefetch",
+        "--cargo-lock",
+        lock(&commit).to_str().unwrap(),
+        "--cargo-config",
+        "-",
+    ]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn prefetch_pinned_commits() {
+    let env = Env::new();
+    let commits = env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+    let url = format!("file://{}", upstream.display());
+
+    // a commit that isn't reachable from any advertised ref
+    let worktree = env.path("repo.worktree");
+    env.run_git(
+        &worktree,
+        &["commit", "-q", "--allow-empty", "-m", "hidden"],
+    );
+    let hidden = env.run_git(&worktree, &["rev-parse", "HEAD"]);
+    env.run_git(
+        &worktree,
+        &[
+            "push",
+            "-q",
+            upstream.to_str().unwrap(),
+            "HEAD:refs/hidden/commit",
+        ],
+    );
+    env.run_git(&upstream, &["config", "uploadpack.hideRefs", "refs/hidden"]);
+    env.run_git(
+        &upstream,
+        &["config", "uploadpack.allowAnySHA1InWant", "true"],
+    );
+
+    env.run_git_cache(&[
+        "prefetch",
+        &format!("{url}@main"),
+        &format!("{url}@{}", commits[0]),
+    ]);
+    let mirror = env.mirror("repo");
+    assert!(mirror.is_dir());
+    let has_hidden = || {
+        env.git(&mirror)
+            .args(["cat-file", "-e", &format!("{hidden}^{{commit}}")])
+            .status()
+            .unwrap()
+            .success()
+    };
+    assert!(!has_hidden());
+
+    env.run_git_cache(&["prefetch", &format!("{url}@{hidden}")]);
+    assert!(has_hidden());
+
+    let missing = "0123456789012345678901234567890123456789";
+    let output = env.git_cache(&[
+        "prefetch",
+        &format!("{url}@main"),
+        &format!("{url}@{missing}"),
+    ]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(&format!("git-cache: prefetched {url}@main")));
+    assert!(stdout.contains("git-cache: 1 of 2 repositories could not be prefetched:"));
+    assert!(stdout.contains(&format!("git-cache:   {url}@{missing}: ")));
+}
+
+#[test]
+fn prefetch_keep_going() {
+    let env = Env::new();
+    env.create_upstream("repo", &["a"]);
+    let upstream = env.upstream("repo");
+    let missing = env.upstream("missing");
+    let args = [
+        "prefetch",
+        missing.to_str().unwrap(),
+        upstream.to_str().unwrap(),
+    ];
+
+    let output = env.git_cache(&args);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("2 of 2 repositories could not be prefetched"));
+    assert!(stdout.contains(&format!("{}: skipped", upstream.display())));
+    assert!(!env.mirror("repo").exists());
+
+    let output = env.git_cache(&[&args[..], &["--keep-going"]].concat());
+    assert!(!output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("1 of 2 repositories could not be prefetched"));
+    assert!(env.mirror("repo").is_dir());
+}
+
+#[test]
+fn prefetch_submodules_of_tags() {
+    let env = Env::new();
+    env.create_upstream("old", &["a"]);
+    let old = env.upstream("old");
+
+    // the release pins a commit that no (advertised) ref of `old` points to
+    let old_worktree = env.path("old.worktree");
+    env.run_git(
+        &old_worktree,
+        &["commit", "-q", "--allow-empty", "-m", "fix"],
+    );
+    let pinned = env.run_git(&old_worktree, &["rev-parse", "HEAD"]);
+    env.run_git(
+        &old_worktree,
+        &["push", "-q", old.to_str().unwrap(), "HEAD:refs/hidden/fix"],
+    );
+    env.run_git(&old, &["config", "uploadpack.hideRefs", "refs/hidden"]);
+    env.run_git(&old, &["config", "uploadpack.allowAnySHA1InWant", "true"]);
+
+    // only the tag `v1` of `super` has the submodule
+    env.create_upstream("super", &["README"]);
+    let worktree = env.path("super.worktree");
+    env.run_git(&worktree, &["checkout", "-q", "-b", "release"]);
+    env.run_git(
+        &worktree,
+        &["submodule", "add", "-q", old.to_str().unwrap(), "old"],
+    );
+    env.run_git(&worktree.join("old"), &["checkout", "-q", &pinned]);
+    env.run_git(
+        &worktree,
+        &[
+            "config",
+            "-f",
+            ".gitmodules",
+            "submodule.old.url",
+            &format!("file://{}", old.display()),
+        ],
+    );
+    env.run_git(&worktree, &["commit", "-q", "-a", "-m", "add old"]);
+    env.run_git(&worktree, &["tag", "v1"]);
+    let upstream = env.upstream("super");
+    env.run_git(&worktree, &["push", "-q", upstream.to_str().unwrap(), "v1"]);
+    let url = format!("file://{}", upstream.display());
+
+    env.run_git_cache(&["prefetch", "--recurse-submodules", &url]);
+    assert!(!env.mirror("old").exists());
+
+    env.run_git_cache(&["prefetch", "--submodule-refs", "refs/tags/v*", &url]);
+    let mirror = env.mirror("old");
+    env.run_git(
+        &mirror,
+        &["cat-file", "-e", &format!("{pinned}^{{commit}}")],
+    );
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::Env;
+
+#[test]
+fn sync_twice() {
+    let env = Env::new();
+    env.create_upstream("app", &["a"]);
+    let lib = env.create_upstream("lib", &["a", "b"]);
+    let sub = env.create_upstream("sub", &["a"]);
+    env.create_superproject("super", &[("sub", "sub", "../sub.git", &sub[0])]);
+
+    let manifest = env.path("manifest.toml");
+    std::fs::write(
+        &manifest,
+        format!(
+            "[[repository]]\nurl = \"{}\"\nbranch = \"main\"\n\n\
+             [[repository]]\nurl = \"{}\"\npath = \"libs/lib\"\ncommit = \"{}\"\n\n\
+             [[repository]]\nurl = \"{}\"\nsubmodules = true\n",
+            env.upstream("app").display(),
+            env.upstream("lib").display(),
+            lib[0],
+            env.upstream("super").display(),
+        ),
+    )
+    .unwrap();
+    let sync = |update: bool| {
+        let mut args = vec!["sync", manifest.to_str().unwrap(), "-j2"];
+        if update {
+            args.push("-U");
+        }
+        env.run_git_cache(&args);
+    };
+    let head = |path: &str| env.run_git(&env.path("work").join(path), &["rev-parse", "HEAD"]);
+
+    sync(false);
+    assert!(env.path("work/app/a").exists());
+    assert_eq!(head("libs/lib"), lib[0]);
+    assert_eq!(head("super/sub"), sub[0]);
+
+    // running again changes nothing
+    let app = head("app");
+    sync(false);
+    assert_eq!(head("app"), app);
+    assert_eq!(head("libs/lib"), lib[0]);
+    assert_eq!(head("super/sub"), sub[0]);
+    assert_eq!(
+        env.run_git(&env.path("work/app"), &["branch", "--show-current"]),
+        "main"
+    );
+
+    // branches are fast-forwarded, submodules follow their superproject
+    let app = env.advance_upstream("app", "b");
+    let sub = env.advance_upstream("sub", "b");
+    let worktree = env.path("super.worktree");
+    env.run_git(&worktree.join("sub"), &["fetch", "-q", "origin"]);
+    env.run_git(&worktree.join("sub"), &["checkout", "-q", &sub]);
+    env.run_git(&worktree, &["commit", "-q", "-a", "-m", "update sub"]);
+    let upstream = env.upstream("super");
+    env.run_git(
+        &worktree,
+        &["push", "-q", upstream.to_str().unwrap(), "main"],
+    );
+
+    sync(true);
+    assert_eq!(head("app"), app);
+    assert_eq!(
+        env.run_git(&env.path("work/app"), &["branch", "--show-current"]),
+        "main"
+    );
+    assert_eq!(head("libs/lib"), lib[0]);
+    assert_eq!(head("super/sub"), sub);
+}
+
+#[test]
+fn sync_yaml_manifest() {
+    let env = Env::new();
+    let commits = env.create_upstream("app", &["a", "b"]);
+
+    let manifest = env.path("manifest.yml");
+    std::fs::write(
+        &manifest,
+        format!(
+            "repository:\n  - url: {}\n    path: app\n    commit: {}\n",
+            env.upstream("app").display(),
+            commits[0]
+        ),
+    )
+    .unwrap();
+    env.run_git_cache(&["sync", manifest.to_str().unwrap()]);
+    assert_eq!(
+        env.run_git(&env.path("work/app"), &["rev-parse", "HEAD"]),
+        commits[0]
+    );
+}
+
+#[test]
+fn sync_nested_submodules_keep_filter() {
+    let env = Env::new();
+    let n = env.create_upstream("n", &["a"]);
+    let m = env.create_upstream("m", &["a"]);
+    let mid = env.create_superproject(
+        "mid",
+        &[("n", "n", "../n.git", &n[0]), ("m", "m", "../m.git", &m[0])],
+    );
+    env.create_superproject("top", &[("mid", "mid", "../mid.git", &mid)]);
+
+    let manifest = env.path("manifest.toml");
+    std::fs::write(
+        &manifest,
+        format!(
+            "[[repository]]\nurl = \"{}\"\nsubmodules = [\"mid/n\"]\n",
+            env.upstream("top").display(),
+        ),
+    )
+    .unwrap();
+
+    // the second run updates the existing checkouts
+    for _ in 0..2 {
+        env.run_git_cache(&["sync", manifest.to_str().unwrap()]);
+        assert!(env.path("work/top/mid/n/a").exists());
+        assert!(!env.path("work/top/mid/m/a").exists());
+    }
+}